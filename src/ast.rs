@@ -1,10 +1,72 @@
+use std::fmt;
+
 use anyhow::Result;
+use lazy_static::lazy_static;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use pest::error::{ErrorVariant, LineColLocation};
+use pest::prec_climber::{Assoc, Operator, PrecClimber};
 use pest::Parser;
+use thiserror::Error;
 
 #[derive(Parser)]
 #[grammar = "rundown.pest"]
 pub struct LanguageParser;
 
+lazy_static! {
+    /// Binary operators from lowest to highest precedence, all of which are left associative
+    static ref PREC_CLIMBER: PrecClimber<Rule> = PrecClimber::new(vec![
+        Operator::new(Rule::or, Assoc::Left),
+        Operator::new(Rule::and, Assoc::Left),
+        Operator::new(Rule::equals, Assoc::Left) | Operator::new(Rule::not_equals, Assoc::Left),
+        Operator::new(Rule::greater_than_equals, Assoc::Left)
+            | Operator::new(Rule::less_than_equals, Assoc::Left)
+            | Operator::new(Rule::greater_than, Assoc::Left)
+            | Operator::new(Rule::less_than, Assoc::Left)
+            | Operator::new(Rule::in_operator, Assoc::Left),
+        Operator::new(Rule::add, Assoc::Left) | Operator::new(Rule::subtract, Assoc::Left),
+        Operator::new(Rule::multiply, Assoc::Left)
+            | Operator::new(Rule::divide, Assoc::Left)
+            | Operator::new(Rule::modulo, Assoc::Left),
+    ]);
+}
+
+/// Location of a node in the Markdown file, with 1-indexed lines and columns
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Span {
+    fn from_pair(pair: &pest::iterators::Pair<Rule>, first_line: usize) -> Self {
+        let span = pair.as_span();
+        let (line, column) = span.start_pos().line_col();
+        let (end_line, end_column) = span.end_pos().line_col();
+        Self {
+            line: line + first_line - 1,
+            column,
+            end_line: end_line + first_line - 1,
+            end_column,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{message}")]
+pub struct SyntaxError {
+    pub span: Span,
+    pub message: String,
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum BinaryOperator {
     Add,
@@ -20,6 +82,29 @@ pub enum BinaryOperator {
     LessThan,
     GreaterThanEquals,
     LessThanEquals,
+    In,
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+            BinaryOperator::And => "&&",
+            BinaryOperator::Or => "||",
+            BinaryOperator::Equals => "==",
+            BinaryOperator::NotEquals => "!=",
+            BinaryOperator::GreaterThan => ">",
+            BinaryOperator::LessThan => "<",
+            BinaryOperator::GreaterThanEquals => ">=",
+            BinaryOperator::LessThanEquals => "<=",
+            BinaryOperator::In => "in",
+        };
+        write!(f, "{}", symbol)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -28,8 +113,24 @@ pub enum UnaryOperator {
     Negate,
 }
 
+impl fmt::Display for UnaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            UnaryOperator::Not => "!",
+            UnaryOperator::Negate => "-",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
-pub enum Expression {
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ExpressionKind {
     BinaryExpression {
         operator: BinaryOperator,
         left: Box<Expression>,
@@ -43,12 +144,29 @@ pub enum Expression {
         name: String,
         arguments: Vec<Expression>,
     },
+    Index {
+        target: Box<Expression>,
+        index: Box<Expression>,
+    },
+    List(Vec<Expression>),
+    Map(Vec<(Expression, Expression)>),
     Ident(String),
     Str(String),
+    /// A string literal containing `${}` interpolations
+    Interpolation(Vec<StringPart>),
     Int(i64),
+    /// An int literal too large for an int, which can only be used with big integers
+    BigInt(BigInt),
+    Float(f64),
     Bool(bool),
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub enum StringPart {
+    Literal(String),
+    Expression(Expression),
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum ScopeSpecifier {
     Global,
@@ -57,8 +175,17 @@ pub enum ScopeSpecifier {
 }
 
 #[derive(PartialEq, Debug, Clone)]
-pub enum Statement {
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum StatementKind {
     Goto(Box<Expression>),
+    /// Run a section, then carry on after the code block which called it
+    Call(Box<Expression>),
+    ReturnSection,
     Declare {
         scope: ScopeSpecifier,
         name: String,
@@ -66,6 +193,10 @@ pub enum Statement {
     },
     Assignment {
         name: String,
+        /// Indices into the variable, for assigning to an element of a list or map
+        indices: Vec<Expression>,
+        /// The operator of a compound assignment such as `+=`, applied to the current value
+        operator: Option<BinaryOperator>,
         expression: Box<Expression>,
     },
     If {
@@ -73,41 +204,174 @@ pub enum Statement {
         statements: Vec<Statement>,
         else_statements: Option<Vec<Statement>>,
     },
+    While {
+        conditional: Box<Expression>,
+        statements: Vec<Statement>,
+    },
+    For {
+        variable: String,
+        iterable: Iterable,
+        statements: Vec<Statement>,
+    },
     FunctionDefinition {
         name: String,
         parameters: Vec<String>,
         statements: Vec<Statement>,
+        /// Text of the whole definition, so that functions can be saved and parsed again
+        source: String,
+    },
+    Match {
+        expression: Box<Expression>,
+        arms: Vec<MatchArm>,
     },
     Expression(Box<Expression>),
     Return(Box<Expression>),
+    Break,
+    Continue,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct MatchArm {
+    pub patterns: Vec<Pattern>,
+    pub statements: Vec<Statement>,
 }
 
-pub fn parse(source: &str) -> Result<Vec<Statement>> {
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Pattern {
+    Wildcard,
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+/// What a `for` loop iterates over
+#[derive(PartialEq, Debug, Clone)]
+pub enum Iterable {
+    /// Integers from `start` up to but not including `end`
+    Range {
+        start: Box<Expression>,
+        end: Box<Expression>,
+    },
+    Expression(Box<Expression>),
+}
+
+/// Parse the contents of a code block, where `first_line` is the line of the Markdown file on which
+/// the code block begins
+pub fn parse(source: &str, first_line: usize) -> Result<Vec<Statement>> {
     let mut ast = vec![];
 
-    let pairs = LanguageParser::parse(Rule::language, source)?;
+    let pairs = LanguageParser::parse(Rule::language, source)
+        .map_err(|e| syntax_error_from_pest(e, first_line))?;
+    check_literals(pairs.clone(), first_line)?;
+
     for pair in pairs {
         if pair.as_rule() == Rule::language {
             for statement in pair.into_inner().next().unwrap().into_inner() {
-                ast.push(build_statement_from_pair(
-                    statement.into_inner().next().unwrap(),
-                ));
+                ast.push(build_statement_from_pair(statement, first_line));
             }
         }
     }
     Ok(ast)
 }
 
-fn build_statement_from_pair(pair: pest::iterators::Pair<Rule>) -> Statement {
-    match pair.as_rule() {
-        Rule::statement => build_statement_from_pair(pair.into_inner().next().unwrap()),
+/// Parse a lone expression, without a trailing semicolon
+pub fn parse_expression(source: &str, first_line: usize) -> Result<Expression> {
+    let pairs = LanguageParser::parse(Rule::single_expression, source)
+        .map_err(|e| syntax_error_from_pest(e, first_line))?;
+    check_literals(pairs.clone(), first_line)?;
+
+    let pair = pairs
+        .into_iter()
+        .next()
+        .unwrap()
+        .into_inner()
+        .next()
+        .unwrap();
+    Ok(get_expression_from_pair(pair, first_line))
+}
+
+/// Check literals up front, so that building the AST can assume they are valid
+fn check_literals(pairs: pest::iterators::Pairs<Rule>, first_line: usize) -> Result<()> {
+    for pair in pairs.flatten() {
+        let message = match pair.as_rule() {
+            Rule::signed_int if pair.as_str().parse::<i64>().is_err() => {
+                format!("Integer literal {} is too large", pair.as_str())
+            }
+            Rule::literal => match unescape(pair.as_str()) {
+                Ok(_) => continue,
+                Err(message) => message,
+            },
+            _ => continue,
+        };
+        return Err(SyntaxError {
+            span: Span::from_pair(&pair, first_line),
+            message,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+fn syntax_error_from_pest(error: pest::error::Error<Rule>, first_line: usize) -> SyntaxError {
+    let ((line, column), (end_line, end_column)) = match error.line_col {
+        LineColLocation::Pos(pos) => (pos, pos),
+        LineColLocation::Span(start, end) => (start, end),
+    };
+    let span = Span {
+        line: line + first_line - 1,
+        column,
+        end_line: end_line + first_line - 1,
+        end_column,
+    };
+
+    let rules_to_string = |rules: &[Rule]| {
+        rules
+            .iter()
+            .map(|rule| format!("{:?}", rule))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let message = match error.variant {
+        ErrorVariant::ParsingError {
+            positives,
+            negatives,
+        } => match (positives.is_empty(), negatives.is_empty()) {
+            (false, _) => format!(
+                "Syntax error, expected one of: {}",
+                rules_to_string(&positives)
+            ),
+            (true, false) => format!("Syntax error, unexpected {}", rules_to_string(&negatives)),
+            (true, true) => "Syntax error".to_owned(),
+        },
+        ErrorVariant::CustomError { message } => message,
+    };
+
+    SyntaxError { span, message }
+}
+
+fn build_statement_from_pair(pair: pest::iterators::Pair<Rule>, first_line: usize) -> Statement {
+    if pair.as_rule() == Rule::statement {
+        return build_statement_from_pair(pair.into_inner().next().unwrap(), first_line);
+    }
+
+    let span = Span::from_pair(&pair, first_line);
+    let kind = match pair.as_rule() {
         Rule::goto_statement => {
             let mut pair = pair.into_inner();
             let label_pair = pair.next().unwrap();
-            let label = get_expression_from_pair(label_pair);
-            Statement::Goto(Box::new(label))
+            let label = get_expression_from_pair(label_pair, first_line);
+            StatementKind::Goto(Box::new(label))
         }
+        Rule::call_statement => {
+            let mut pair = pair.into_inner().skip(1);
+            let label_pair = pair.next().unwrap();
+            let label = get_expression_from_pair(label_pair, first_line);
+            StatementKind::Call(Box::new(label))
+        }
+        Rule::return_section_statement => StatementKind::ReturnSection,
         Rule::function_definition => {
+            let source = pair.as_str().to_owned();
             let mut pair = pair.into_inner();
             let name_pair = pair.next().unwrap();
             let name = get_ident_from_pair(name_pair);
@@ -117,60 +381,144 @@ fn build_statement_from_pair(pair: pest::iterators::Pair<Rule>) -> Statement {
                 .map(get_ident_from_pair)
                 .collect();
             let statements_pair = pair.next().unwrap();
-            let statements: Vec<Statement> = statements_pair
-                .into_inner()
-                .map(build_statement_from_pair)
-                .collect();
-            Statement::FunctionDefinition {
+            let statements = build_statements_from_pair(statements_pair, first_line);
+            StatementKind::FunctionDefinition {
                 name,
                 parameters,
                 statements,
+                source,
             }
         }
         Rule::if_statement => {
             let mut pair = pair.into_inner();
             let conditional_pair = pair.next().unwrap();
-            let conditional = Box::new(get_expression_from_pair(conditional_pair));
+            let conditional = Box::new(get_expression_from_pair(conditional_pair, first_line));
             let statements_pair = pair.next().unwrap();
-            let statements: Vec<Statement> = statements_pair
-                .into_inner()
-                .map(build_statement_from_pair)
-                .collect();
+            let statements = build_statements_from_pair(statements_pair, first_line);
             let else_statements = if let Some(else_pair) = pair.next() {
                 match else_pair.as_rule() {
-                    Rule::else_statement => Some(
-                        else_pair
-                            .into_inner()
-                            .next()
-                            .unwrap()
-                            .into_inner()
-                            .map(build_statement_from_pair)
-                            .collect(),
-                    ),
+                    Rule::else_statement => {
+                        let else_pair = else_pair.into_inner().next().unwrap();
+                        match else_pair.as_rule() {
+                            // An `else if` is an else branch containing only another if statement
+                            Rule::if_statement => {
+                                Some(vec![build_statement_from_pair(else_pair, first_line)])
+                            }
+                            _ => Some(build_statements_from_pair(else_pair, first_line)),
+                        }
+                    }
                     _ => None,
                 }
             } else {
                 None
             };
-            Statement::If {
+            StatementKind::If {
                 conditional,
                 statements,
                 else_statements,
             }
         }
+        Rule::while_statement => {
+            let mut pair = pair.into_inner();
+            let conditional_pair = pair.next().unwrap();
+            let conditional = Box::new(get_expression_from_pair(conditional_pair, first_line));
+            let statements_pair = pair.next().unwrap();
+            let statements = build_statements_from_pair(statements_pair, first_line);
+            StatementKind::While {
+                conditional,
+                statements,
+            }
+        }
+        Rule::for_statement => {
+            let mut pair = pair.into_inner();
+            let variable_pair = pair.next().unwrap();
+            let variable = get_ident_from_pair(variable_pair);
+            let iterable_pair = pair.nth(1).unwrap();
+            let iterable = match iterable_pair.as_rule() {
+                Rule::range => {
+                    let mut pair = iterable_pair.into_inner();
+                    let start = get_expression_from_pair(pair.next().unwrap(), first_line);
+                    let end = get_expression_from_pair(pair.next().unwrap(), first_line);
+                    Iterable::Range {
+                        start: Box::new(start),
+                        end: Box::new(end),
+                    }
+                }
+                _ => Iterable::Expression(Box::new(get_expression_from_pair(
+                    iterable_pair,
+                    first_line,
+                ))),
+            };
+            let statements_pair = pair.next().unwrap();
+            let statements = build_statements_from_pair(statements_pair, first_line);
+            StatementKind::For {
+                variable,
+                iterable,
+                statements,
+            }
+        }
+        Rule::match_statement => {
+            let mut pair = pair.into_inner();
+            let expression_pair = pair.next().unwrap();
+            let expression = Box::new(get_expression_from_pair(expression_pair, first_line));
+            let arms = pair
+                .map(|arm_pair| {
+                    let mut arm_pair = arm_pair.into_inner();
+                    let patterns = arm_pair
+                        .next()
+                        .unwrap()
+                        .into_inner()
+                        .map(get_pattern_from_pair)
+                        .collect();
+                    let body_pair = arm_pair.next().unwrap();
+                    let statements = match body_pair.as_rule() {
+                        Rule::list_statement => build_statements_from_pair(body_pair, first_line),
+                        _ => vec![build_statement_from_pair(body_pair, first_line)],
+                    };
+                    MatchArm {
+                        patterns,
+                        statements,
+                    }
+                })
+                .collect();
+            StatementKind::Match { expression, arms }
+        }
+        Rule::break_statement => StatementKind::Break,
+        Rule::continue_statement => StatementKind::Continue,
         Rule::return_statement => {
             let mut pair = pair.into_inner();
             let expression_pair = pair.next().unwrap();
-            let expression = get_expression_from_pair(expression_pair);
-            Statement::Return(Box::new(expression))
+            let expression = get_expression_from_pair(expression_pair, first_line);
+            StatementKind::Return(Box::new(expression))
         }
         Rule::assignment => {
             let mut pair = pair.into_inner();
             let name_pair = pair.next().unwrap();
             let name = get_ident_from_pair(name_pair);
+            let mut indices = vec![];
+            let mut operator_pair = pair.next().unwrap();
+            while operator_pair.as_rule() == Rule::index {
+                let index_pair = operator_pair.into_inner().next().unwrap();
+                indices.push(get_expression_from_pair(index_pair, first_line));
+                operator_pair = pair.next().unwrap();
+            }
+            let operator = match operator_pair.as_str() {
+                "=" => None,
+                "+=" => Some(BinaryOperator::Add),
+                "-=" => Some(BinaryOperator::Subtract),
+                "*=" => Some(BinaryOperator::Multiply),
+                "/=" => Some(BinaryOperator::Divide),
+                "%=" => Some(BinaryOperator::Modulo),
+                unknown_operator => panic!("Unknown assignment operator: {}", unknown_operator),
+            };
             let expression_pair = pair.next().unwrap();
-            let expression = Box::new(get_expression_from_pair(expression_pair));
-            Statement::Assignment { name, expression }
+            let expression = Box::new(get_expression_from_pair(expression_pair, first_line));
+            StatementKind::Assignment {
+                name,
+                indices,
+                operator,
+                expression,
+            }
         }
         Rule::declare => {
             let mut pair = pair.into_inner();
@@ -184,35 +532,53 @@ fn build_statement_from_pair(pair: pest::iterators::Pair<Rule>) -> Statement {
             let name_pair = pair.next().unwrap();
             let name = get_ident_from_pair(name_pair);
             let expression_pair = pair.next().unwrap();
-            let expression = Box::new(get_expression_from_pair(expression_pair));
-            Statement::Declare {
+            let expression = Box::new(get_expression_from_pair(expression_pair, first_line));
+            StatementKind::Declare {
                 scope,
                 name,
                 expression,
             }
         }
-        Rule::expression => Statement::Expression(Box::new(get_expression_from_pair(pair))),
+        Rule::expression => {
+            StatementKind::Expression(Box::new(get_expression_from_pair(pair, first_line)))
+        }
         unknown_statement => panic!("Unexpected statement: {:?}", unknown_statement),
-    }
+    };
+
+    Statement { kind, span }
+}
+
+fn build_statements_from_pair(
+    pair: pest::iterators::Pair<Rule>,
+    first_line: usize,
+) -> Vec<Statement> {
+    pair.into_inner()
+        .map(|statement| build_statement_from_pair(statement, first_line))
+        .collect()
 }
 
-fn get_expression_from_pair(pair: pest::iterators::Pair<Rule>) -> Expression {
+fn get_expression_from_pair(pair: pest::iterators::Pair<Rule>, first_line: usize) -> Expression {
     match pair.as_rule() {
-        Rule::expression => get_expression_from_pair(pair.into_inner().next().unwrap()),
-        Rule::binary_expression => {
-            let mut pair = pair.into_inner();
-            let left_pair = pair.next().unwrap();
-            let left = get_expression_from_pair(left_pair);
-            let operator_pair = pair.next().unwrap();
-            let right_pair = pair.next().unwrap();
-            let right = get_expression_from_pair(right_pair);
-            parse_operator_expression(operator_pair, left, right)
+        Rule::expression => {
+            return PREC_CLIMBER.climb(
+                pair.into_inner(),
+                |operand| get_expression_from_pair(operand, first_line),
+                parse_operator_expression,
+            )
         }
+        Rule::term => {
+            return get_expression_from_pair(pair.into_inner().next().unwrap(), first_line)
+        }
+        _ => (),
+    }
+
+    let span = Span::from_pair(&pair, first_line);
+    let kind = match pair.as_rule() {
         Rule::unary_expression => {
             let mut pair = pair.into_inner();
             let operator_pair = pair.next().unwrap();
             let expression_pair = pair.next().unwrap();
-            let expression = get_expression_from_pair(expression_pair);
+            let expression = get_expression_from_pair(expression_pair, first_line);
             parse_unary_operator_expression(operator_pair, expression)
         }
         Rule::function_call => {
@@ -222,45 +588,89 @@ fn get_expression_from_pair(pair: pest::iterators::Pair<Rule>) -> Expression {
             let arguments_pair = pair.next().unwrap();
             let arguments: Vec<Expression> = arguments_pair
                 .into_inner()
-                .map(get_expression_from_pair)
+                .map(|argument| get_expression_from_pair(argument, first_line))
                 .collect();
-            Expression::FunctionCall { name, arguments }
+            ExpressionKind::FunctionCall { name, arguments }
         }
-        Rule::term => get_expression_from_pair(pair.into_inner().next().unwrap()),
-        Rule::string => {
-            let str = &pair.as_str();
-            let str = &str[1..str.len() - 1];
-            let str = str.replace("''", "'");
-            Expression::Str(str)
+        Rule::index_expression => {
+            let mut pair = pair.into_inner();
+            let target_pair = pair.next().unwrap();
+            let mut target = get_expression_from_pair(target_pair, first_line);
+            for index_pair in pair {
+                let index_span = Span::from_pair(&index_pair, first_line);
+                let index =
+                    get_expression_from_pair(index_pair.into_inner().next().unwrap(), first_line);
+                target = Expression {
+                    span: Span {
+                        line: target.span.line,
+                        column: target.span.column,
+                        end_line: index_span.end_line,
+                        end_column: index_span.end_column,
+                    },
+                    kind: ExpressionKind::Index {
+                        target: Box::new(target),
+                        index: Box::new(index),
+                    },
+                };
+            }
+            return target;
         }
+        Rule::list => ExpressionKind::List(
+            pair.into_inner()
+                .map(|element| get_expression_from_pair(element, first_line))
+                .collect(),
+        ),
+        Rule::map => ExpressionKind::Map(
+            pair.into_inner()
+                .map(|entry| {
+                    let mut entry = entry.into_inner();
+                    let key = get_expression_from_pair(entry.next().unwrap(), first_line);
+                    let value = get_expression_from_pair(entry.next().unwrap(), first_line);
+                    (key, value)
+                })
+                .collect(),
+        ),
+        Rule::string => get_string_from_pair(pair, first_line),
         Rule::ident => {
             let str = pair.as_str();
-            Expression::Ident(String::from(str))
+            ExpressionKind::Ident(String::from(str))
         }
         Rule::int => {
             let str = pair.as_str().trim();
-            Expression::Int(str.parse::<i64>().unwrap())
+            match str.parse::<i64>() {
+                Ok(i) => ExpressionKind::Int(i),
+                Err(_) => ExpressionKind::BigInt(str.parse::<BigInt>().unwrap()),
+            }
         }
+        Rule::float => ExpressionKind::Float(pair.as_str().parse::<f64>().unwrap()),
         Rule::boolean => {
             let str = pair.as_str();
             match str {
-                "true" => Expression::Bool(true),
-                _ => Expression::Bool(false),
+                "true" => ExpressionKind::Bool(true),
+                _ => ExpressionKind::Bool(false),
             }
         }
         unknown_expression => panic!("Unexpected statement: {:?}", unknown_expression),
-    }
+    };
+
+    Expression { kind, span }
 }
 
 fn parse_operator_expression(
-    operator_pair: pest::iterators::Pair<Rule>,
     left: Expression,
+    operator_pair: pest::iterators::Pair<Rule>,
     right: Expression,
 ) -> Expression {
-    Expression::BinaryExpression {
+    let span = Span {
+        line: left.span.line,
+        column: left.span.column,
+        end_line: right.span.end_line,
+        end_column: right.span.end_column,
+    };
+    let kind = ExpressionKind::BinaryExpression {
         left: Box::new(left),
         right: Box::new(right),
-        operator: match operator_pair.into_inner().next().unwrap().as_rule() {
+        operator: match operator_pair.as_rule() {
             Rule::add => BinaryOperator::Add,
             Rule::subtract => BinaryOperator::Subtract,
             Rule::multiply => BinaryOperator::Multiply,
@@ -274,26 +684,465 @@ fn parse_operator_expression(
             Rule::less_than_equals => BinaryOperator::LessThanEquals,
             Rule::greater_than => BinaryOperator::GreaterThan,
             Rule::less_than => BinaryOperator::LessThan,
+            Rule::in_operator => BinaryOperator::In,
             unknown_operator => panic!("Unknown operator: {:?}", unknown_operator),
         },
-    }
+    };
+
+    Expression { kind, span }
 }
 
 fn parse_unary_operator_expression(
     operator_pair: pest::iterators::Pair<Rule>,
     expression: Expression,
-) -> Expression {
-    Expression::UnaryExpression {
+) -> ExpressionKind {
+    let operator = match operator_pair.into_inner().next().unwrap().as_rule() {
+        Rule::not => UnaryOperator::Not,
+        Rule::negate => UnaryOperator::Negate,
+        unknown_operator => panic!("Unknown unary operator: {:?}", unknown_operator),
+    };
+    // The smallest int is written as the negation of a literal which is too large on its own
+    if let (UnaryOperator::Negate, ExpressionKind::BigInt(n)) = (&operator, &expression.kind) {
+        if let Some(i) = (-n).to_i64() {
+            return ExpressionKind::Int(i);
+        }
+    }
+    ExpressionKind::UnaryExpression {
         expression: Box::new(expression),
-        operator: match operator_pair.into_inner().next().unwrap().as_rule() {
-            Rule::not => UnaryOperator::Not,
-            Rule::negate => UnaryOperator::Negate,
-            unknown_operator => panic!("Unknown unary operator: {:?}", unknown_operator),
-        },
+        operator,
+    }
+}
+
+fn get_pattern_from_pair(pair: pest::iterators::Pair<Rule>) -> Pattern {
+    match pair.as_rule() {
+        Rule::wildcard => Pattern::Wildcard,
+        Rule::pattern_string => Pattern::Str(
+            pair.into_inner()
+                .next()
+                .map(|literal| unescape(literal.as_str()).unwrap())
+                .unwrap_or_default(),
+        ),
+        Rule::signed_int => Pattern::Int(pair.as_str().parse::<i64>().unwrap()),
+        Rule::boolean => Pattern::Bool(pair.as_str() == "true"),
+        unknown_pattern => panic!("Unknown pattern: {:?}", unknown_pattern),
+    }
+}
+
+/// Strings without any interpolations are simplified to a plain string literal
+fn get_string_from_pair(pair: pest::iterators::Pair<Rule>, first_line: usize) -> ExpressionKind {
+    let mut parts: Vec<StringPart> = pair
+        .into_inner()
+        .map(|part| match part.as_rule() {
+            Rule::literal => StringPart::Literal(unescape(part.as_str()).unwrap()),
+            Rule::interpolation => StringPart::Expression(get_expression_from_pair(
+                part.into_inner().next().unwrap(),
+                first_line,
+            )),
+            unknown_part => panic!("Unknown string part: {:?}", unknown_part),
+        })
+        .collect();
+
+    match parts.as_mut_slice() {
+        [] => ExpressionKind::Str(String::new()),
+        [StringPart::Literal(s)] => ExpressionKind::Str(std::mem::take(s)),
+        _ => ExpressionKind::Interpolation(parts),
+    }
+}
+
+/// Decode the escape sequences in a literal piece of a string
+fn unescape(literal: &str) -> std::result::Result<String, String> {
+    let mut result = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let decoded = match chars.next().unwrap() {
+            'b' => '\u{8}',
+            'f' => '\u{c}',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                // The grammar guarantees four hex digits after every \u
+                let mut rest = chars.as_str();
+                let mut code = u32::from_str_radix(&rest[..4], 16).unwrap();
+                rest = &rest[4..];
+
+                // Characters outside the basic multilingual plane are written as a surrogate pair
+                if (0xD800..0xDC00).contains(&code) && rest.starts_with("\\u") {
+                    let low = u32::from_str_radix(&rest[2..6], 16).unwrap();
+                    if (0xDC00..0xE000).contains(&low) {
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                        rest = &rest[6..];
+                    }
+                }
+                chars = rest.chars();
+
+                char::from_u32(code)
+                    .ok_or_else(|| format!("Invalid unicode escape \\u{:04X}", code))?
+            }
+            // Quotes, backslashes, slashes and dollar signs stand for themselves
+            c => c,
+        };
+        result.push(decoded);
     }
+
+    Ok(result)
 }
 
 fn get_ident_from_pair(pair: pest::iterators::Pair<Rule>) -> String {
     let str = pair.as_str();
     String::from(str)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render an expression as an s-expression, so that tests can check its shape
+    fn sexp(expression: &Expression) -> String {
+        match &expression.kind {
+            ExpressionKind::BinaryExpression {
+                operator,
+                left,
+                right,
+            } => format!("({} {} {})", operator, sexp(left), sexp(right)),
+            ExpressionKind::UnaryExpression {
+                operator,
+                expression,
+            } => format!("({} {})", operator, sexp(expression)),
+            ExpressionKind::FunctionCall { name, arguments } => format!(
+                "({}{})",
+                name,
+                arguments
+                    .iter()
+                    .map(|argument| format!(" {}", sexp(argument)))
+                    .collect::<String>()
+            ),
+            ExpressionKind::Index { target, index } => {
+                format!("([] {} {})", sexp(target), sexp(index))
+            }
+            ExpressionKind::List(elements) => format!(
+                "[{}]",
+                elements.iter().map(sexp).collect::<Vec<_>>().join(" ")
+            ),
+            ExpressionKind::Map(entries) => format!(
+                "{{{}}}",
+                entries
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", sexp(key), sexp(value)))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            ExpressionKind::Ident(name) => name.clone(),
+            ExpressionKind::Str(s) => format!("{:?}", s),
+            ExpressionKind::Interpolation(parts) => format!(
+                "(${{}} {})",
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        StringPart::Literal(s) => format!("{:?}", s),
+                        StringPart::Expression(e) => sexp(e),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            ExpressionKind::Int(i) => i.to_string(),
+            ExpressionKind::BigInt(n) => n.to_string(),
+            ExpressionKind::Float(f) => format!("{:?}", f),
+            ExpressionKind::Bool(b) => b.to_string(),
+        }
+    }
+
+    fn parse_expression(source: &str) -> Expression {
+        let statements = parse(&format!("{};", source), 1).unwrap();
+        match &statements[0].kind {
+            StatementKind::Expression(expression) => *expression.clone(),
+            kind => panic!("Expected an expression statement, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn subtraction_is_left_associative() {
+        assert_eq!(sexp(&parse_expression("10 - 2 - 3")), "(- (- 10 2) 3)");
+    }
+
+    #[test]
+    fn division_is_left_associative() {
+        assert_eq!(sexp(&parse_expression("8 / 4 / 2")), "(/ (/ 8 4) 2)");
+        assert_eq!(sexp(&parse_expression("7 % 4 * 2")), "(* (% 7 4) 2)");
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(sexp(&parse_expression("1 + 2 * 3")), "(+ 1 (* 2 3))");
+        assert_eq!(sexp(&parse_expression("1 * 2 + 3")), "(+ (* 1 2) 3)");
+    }
+
+    #[test]
+    fn arithmetic_binds_tighter_than_comparison() {
+        assert_eq!(
+            sexp(&parse_expression("1 + 2 * 3 == 7")),
+            "(== (+ 1 (* 2 3)) 7)"
+        );
+        assert_eq!(
+            sexp(&parse_expression("counter % 3 == 0")),
+            "(== (% counter 3) 0)"
+        );
+    }
+
+    #[test]
+    fn ordering_binds_tighter_than_equality() {
+        assert_eq!(
+            sexp(&parse_expression("1 < 2 == 3 >= 4")),
+            "(== (< 1 2) (>= 3 4))"
+        );
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(sexp(&parse_expression("a || b && c")), "(|| a (&& b c))");
+        assert_eq!(sexp(&parse_expression("a && b || c")), "(|| (&& a b) c)");
+    }
+
+    #[test]
+    fn comparison_binds_tighter_than_logical_operators() {
+        assert_eq!(
+            sexp(&parse_expression("a % 3 == 0 && a % 5 == 0")),
+            "(&& (== (% a 3) 0) (== (% a 5) 0))"
+        );
+    }
+
+    #[test]
+    fn unary_operators_bind_tightest() {
+        assert_eq!(sexp(&parse_expression("!a && b")), "(&& (! a) b)");
+        assert_eq!(sexp(&parse_expression("-2 * 3")), "(* (- 2) 3)");
+        assert_eq!(sexp(&parse_expression("1 - -2")), "(- 1 (- 2))");
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        assert_eq!(sexp(&parse_expression("(1 + 2) * 3")), "(* (+ 1 2) 3)");
+        assert_eq!(sexp(&parse_expression("10 - (2 - 3)")), "(- 10 (- 2 3))");
+    }
+
+    #[test]
+    fn function_arguments_are_full_expressions() {
+        assert_eq!(
+            sexp(&parse_expression("f(1 + 2 * 3, g()) * 2")),
+            "(* (f (+ 1 (* 2 3)) (g)) 2)"
+        );
+    }
+
+    #[test]
+    fn collection_literals_and_indexing() {
+        assert_eq!(
+            sexp(&parse_expression(r#"[1, "a", [true]][2][0]"#)),
+            r#"([] ([] [1 "a" [true]] 2) 0)"#
+        );
+        assert_eq!(
+            sexp(&parse_expression(
+                r#"{"gold": 1 + 2, "items": []}["gold"] * 2"#
+            )),
+            r#"(* ([] {"gold": (+ 1 2) "items": []} "gold") 2)"#
+        );
+        assert_eq!(
+            sexp(&parse_expression(r#""key" in inventory && index"#)),
+            r#"(&& (in "key" inventory) index)"#
+        );
+    }
+
+    #[test]
+    fn string_escapes_and_interpolation() {
+        assert_eq!(
+            sexp(&parse_expression(r#""a\"b\\c\n\t\u00e9\ud83d\ude00\$""#)),
+            format!("{:?}", "a\"b\\c\n\t\u{e9}\u{1f600}$")
+        );
+        assert_eq!(
+            sexp(&parse_expression(
+                r#""Hello ${name}, you have ${ gold * 2 } gold""#
+            )),
+            r#"(${} "Hello " name ", you have " (* gold 2) " gold")"#
+        );
+        assert_eq!(
+            sexp(&parse_expression(r#""${"nested ${x}"}\${x}""#)),
+            r#"(${} (${} "nested " x) "${x}")"#
+        );
+        assert!(parse(r#"print("\ud800");"#, 1).is_err());
+    }
+
+    #[test]
+    fn comments_between_statements() {
+        let source = "
+            // Set up the player
+            let a = 1; /* the starting gold */ let b = 2;
+            /* Spanning
+               several lines */
+            print(a);
+        ";
+        assert_eq!(parse(source, 1).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn comments_inside_expressions() {
+        assert_eq!(
+            sexp(&parse_expression("1 + /* inline */ 2 * // trailing\n 3")),
+            "(+ 1 (* 2 3))"
+        );
+        assert_eq!(
+            sexp(&parse_expression(r#""// not a comment /* either */""#)),
+            r#""// not a comment /* either */""#
+        );
+    }
+
+    #[test]
+    fn comments_at_end_of_block() {
+        assert_eq!(parse("goto \"a\"; // jump", 1).unwrap().len(), 1);
+        assert_eq!(
+            parse("if (a) { b(); /* done */ } /* end */", 1)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(parse("goto \"a\"; /* unterminated", 1).is_err());
+    }
+
+    #[test]
+    fn loops_break_and_continue() {
+        let source = "while (true) { break; } for (i in 0..3) { continue; }";
+        let statements = parse(source, 1).unwrap();
+        match &statements[0].kind {
+            StatementKind::While { statements, .. } => {
+                assert_eq!(statements[0].kind, StatementKind::Break)
+            }
+            kind => panic!("expected a while loop, got {:?}", kind),
+        }
+        match &statements[1].kind {
+            StatementKind::For { statements, .. } => {
+                assert_eq!(statements[0].kind, StatementKind::Continue)
+            }
+            kind => panic!("expected a for loop, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn for_needs_a_word_boundary_after_in() {
+        assert!(parse("for (i inxs) { continue; }", 1).is_err());
+        let statements = parse("for (i in inventory) { continue; }", 1).unwrap();
+        match &statements[0].kind {
+            StatementKind::For { iterable, .. } => assert!(matches!(
+                iterable,
+                Iterable::Expression(expression)
+                    if expression.kind == ExpressionKind::Ident("inventory".to_owned())
+            )),
+            kind => panic!("expected a for loop, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn identifiers_may_start_with_keywords() {
+        let source =
+            "fun breakfast() { return 1; } breakfast(); continue_story(); whiled(); format(1);";
+        let statements = parse(source, 1).unwrap();
+        let names: Vec<_> = statements[1..]
+            .iter()
+            .map(|statement| match &statement.kind {
+                StatementKind::Expression(expression) => match &expression.kind {
+                    ExpressionKind::FunctionCall { name, .. } => name.as_str(),
+                    kind => panic!("expected a function call, got {:?}", kind),
+                },
+                kind => panic!("expected an expression, got {:?}", kind),
+            })
+            .collect();
+        assert_eq!(names, ["breakfast", "continue_story", "whiled", "format"]);
+    }
+
+    #[test]
+    fn int_literals_too_large_for_an_int() {
+        assert_eq!(
+            parse_expression("-9223372036854775808").kind,
+            ExpressionKind::Int(i64::MIN)
+        );
+        assert_eq!(
+            sexp(&parse_expression("9223372036854775808 + 1")),
+            "(+ 9223372036854775808 1)"
+        );
+        assert!(parse("match x { 9223372036854775808 => {} }", 1).is_err());
+    }
+
+    #[test]
+    fn call_and_return_section_are_keywords() {
+        let statements = parse("call \"shop\"; called(1); return_section;", 1).unwrap();
+        assert!(matches!(statements[0].kind, StatementKind::Call(_)));
+        assert!(matches!(
+            &statements[1].kind,
+            StatementKind::Expression(expression)
+                if matches!(&expression.kind, ExpressionKind::FunctionCall { name, .. } if name == "called")
+        ));
+        assert_eq!(statements[2].kind, StatementKind::ReturnSection);
+    }
+
+    #[test]
+    fn call_takes_a_variable_target() {
+        let statements = parse("let label = \"shop\"; call label;", 1).unwrap();
+        match &statements[1].kind {
+            StatementKind::Call(label) => {
+                assert!(matches!(&label.kind, ExpressionKind::Ident(name) if name == "label"))
+            }
+            kind => panic!("expected a call, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn else_if_chains_nest() {
+        let source = r#"
+            if (a == 1) { goto "one"; }
+            else if (a == 2) { goto "two"; }
+            else if (a == 3) { goto "three"; }
+            else { goto "other"; }
+        "#;
+        let statements = parse(source, 1).unwrap();
+        assert_eq!(statements.len(), 1);
+
+        let mut conditionals = vec![];
+        let mut statement = &statements[0];
+        loop {
+            match &statement.kind {
+                StatementKind::If {
+                    conditional,
+                    else_statements: Some(else_statements),
+                    ..
+                } => {
+                    conditionals.push(sexp(conditional));
+                    statement = &else_statements[0];
+                }
+                StatementKind::Goto(label) => {
+                    conditionals.push(sexp(label));
+                    break;
+                }
+                kind => panic!("Unexpected statement {:?}", kind),
+            }
+        }
+        assert_eq!(
+            conditionals,
+            vec!["(== a 1)", "(== a 2)", "(== a 3)", "\"other\""]
+        );
+    }
+
+    #[test]
+    fn binary_expression_spans_its_operands() {
+        let expression = parse_expression("1 + 2 * 3");
+        assert_eq!(
+            expression.span,
+            Span {
+                line: 1,
+                column: 1,
+                end_line: 1,
+                end_column: 10,
+            }
+        );
+    }
+}
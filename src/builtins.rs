@@ -1,63 +1,645 @@
 use std::collections::HashMap;
-use std::io;
-use std::io::BufRead;
-use std::thread;
-use std::time;
+use std::convert::TryFrom;
+use std::time::Duration;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
+use num_bigint::BigInt;
+use num_traits::{Signed, ToPrimitive};
+use rand::seq::SliceRandom;
+use rand::Rng;
 
-use crate::eval::{Builtin, Value};
-use crate::markdown::print_markdown;
+use crate::error::RundownError;
+use crate::eval::{Builtin, Context, ExpressionResult, Value};
 
-pub fn builtins() -> HashMap<String, Builtin> {
-    let mut h: HashMap<String, Builtin> = HashMap::new();
-    h.insert("read".to_owned(), read);
-    h.insert("print".to_owned(), print);
-    h.insert("sleep".to_owned(), sleep);
+pub fn builtins() -> HashMap<String, Box<dyn Builtin>> {
+    let mut h: HashMap<String, Box<dyn Builtin>> = HashMap::new();
+    h.insert("read".to_owned(), Box::new(read));
+    h.insert("read_line".to_owned(), value_builtin(read_line));
+    h.insert("print".to_owned(), Box::new(print));
+    h.insert("sleep".to_owned(), Box::new(sleep));
+    h.insert("clear".to_owned(), Box::new(clear));
+    h.insert("len".to_owned(), value_builtin(len));
+    h.insert("push".to_owned(), value_builtin(push));
+    h.insert("keys".to_owned(), value_builtin(keys));
+    h.insert("int".to_owned(), value_builtin(int));
+    h.insert("float".to_owned(), value_builtin(float));
+    h.insert("round".to_owned(), value_builtin(round));
+    h.insert("str".to_owned(), value_builtin(str));
+    h.insert("upper".to_owned(), value_builtin(upper));
+    h.insert("lower".to_owned(), value_builtin(lower));
+    h.insert("trim".to_owned(), value_builtin(trim));
+    h.insert("contains".to_owned(), value_builtin(contains));
+    h.insert("starts_with".to_owned(), value_builtin(starts_with));
+    h.insert("ends_with".to_owned(), value_builtin(ends_with));
+    h.insert("split".to_owned(), value_builtin(split));
+    h.insert("join".to_owned(), value_builtin(join));
+    h.insert("replace".to_owned(), value_builtin(replace));
+    h.insert("substr".to_owned(), value_builtin(substr));
+    h.insert("repeat".to_owned(), value_builtin(repeat));
+    h.insert("index_of".to_owned(), value_builtin(index_of));
+    h.insert("format".to_owned(), value_builtin(format));
+    h.insert("random".to_owned(), value_builtin(random));
+    h.insert("choice".to_owned(), value_builtin(choice));
+    h.insert("shuffle".to_owned(), value_builtin(shuffle));
+    h.insert("abs".to_owned(), value_builtin(abs));
+    h.insert("min".to_owned(), value_builtin(min));
+    h.insert("max".to_owned(), value_builtin(max));
+    h.insert("pow".to_owned(), value_builtin(pow));
+    h.insert("clamp".to_owned(), value_builtin(clamp));
     h
 }
 
-fn print(arguments: &[Value]) -> Result<Value> {
+/// Adapt a builtin which always returns a value, rather than possibly a goto
+fn value_builtin(f: fn(&mut Context, &[Value]) -> Result<Value>) -> Box<dyn Builtin> {
+    Box::new(move |context: &mut Context, arguments: &[Value]| {
+        f(context, arguments).map(ExpressionResult::Value)
+    })
+}
+
+fn check_arity(name: &str, arguments: &[Value], expected: usize) -> Result<()> {
+    if arguments.len() != expected {
+        bail!(RundownError::ArityMismatch {
+            name: name.to_owned(),
+            expected,
+            actual: arguments.len(),
+        });
+    }
+
+    Ok(())
+}
+
+fn string_argument<'a>(name: &str, arguments: &'a [Value], position: usize) -> Result<&'a str> {
+    match &arguments[position] {
+        Value::Str(s) => Ok(s),
+        v => bail!(RundownError::InvalidArgument {
+            name: name.to_owned(),
+            expected: "a string",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+fn int_argument(name: &str, arguments: &[Value], position: usize) -> Result<i64> {
+    match &arguments[position] {
+        Value::Int(i) => Ok(*i),
+        v => bail!(RundownError::InvalidArgument {
+            name: name.to_owned(),
+            expected: "an int",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+fn number_argument(name: &str, arguments: &[Value], position: usize) -> Result<f64> {
+    match &arguments[position] {
+        Value::Int(i) => Ok(*i as f64),
+        Value::BigInt(n) => Ok(n.to_f64().unwrap_or(f64::NAN)),
+        Value::Float(f) => Ok(*f),
+        v => bail!(RundownError::InvalidArgument {
+            name: name.to_owned(),
+            expected: "a number",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+fn list_argument<'a>(name: &str, arguments: &'a [Value], position: usize) -> Result<&'a [Value]> {
+    match &arguments[position] {
+        Value::List(l) => Ok(l),
+        v => bail!(RundownError::InvalidArgument {
+            name: name.to_owned(),
+            expected: "a list",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+fn print(context: &mut Context, arguments: &[Value]) -> Result<ExpressionResult> {
     let output = arguments
         .iter()
         .map(|arg| arg.to_string())
         .collect::<Vec<_>>()
         .join(" ");
-    print_markdown(&output[..])?;
-    Ok(Value::Bool(true))
+    context.io.write_markdown(&output[..])?;
+    Ok(ExpressionResult::Value(Value::Bool(true)))
 }
 
-fn read(_arguments: &[Value]) -> Result<Value> {
-    let mut buffer = String::new();
-    let stdin = io::stdin();
-    stdin.lock().read_line(&mut buffer)?;
-    buffer = buffer.trim().to_lowercase().to_string();
+fn read(context: &mut Context, _arguments: &[Value]) -> Result<ExpressionResult> {
+    let buffer = context.io.read_line()?.trim().to_lowercase();
 
     // Do some dynamic typing magic
-    if buffer == "true" {
-        Ok(Value::Bool(true))
+    let value = if buffer == "true" {
+        Value::Bool(true)
     } else if buffer == "false" {
-        Ok(Value::Bool(false))
+        Value::Bool(false)
     } else if let Ok(i) = buffer.parse::<i64>() {
-        Ok(Value::Int(i))
+        Value::Int(i)
+    } else if let (true, Ok(f)) = (is_decimal(&buffer), buffer.parse::<f64>()) {
+        Value::Float(f)
     } else {
-        Ok(Value::Str(buffer))
-    }
+        Value::Str(buffer)
+    };
+    Ok(ExpressionResult::Value(value))
 }
 
-fn sleep(arguments: &[Value]) -> Result<Value> {
-    if arguments.len() != 1 {
-        bail!("Invalid arguments");
+/// `read_line()` reads an answer exactly as it was typed, for names and other free text
+fn read_line(context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("read_line", arguments, 0)?;
+    Ok(Value::Str(context.io.read_line()?))
+}
+
+/// Only plain decimals like `2.5` are read as floats, so answers like `inf` or `1e3` stay strings
+fn is_decimal(s: &str) -> bool {
+    let is_digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    match s.strip_prefix('-').unwrap_or(s).split_once('.') {
+        Some((whole, fraction)) => is_digits(whole) && is_digits(fraction),
+        None => false,
     }
+}
+
+fn clear(context: &mut Context, arguments: &[Value]) -> Result<ExpressionResult> {
+    check_arity("clear", arguments, 0)?;
+
+    context.io.clear()?;
+    Ok(ExpressionResult::Value(Value::Bool(true)))
+}
+
+fn sleep(context: &mut Context, arguments: &[Value]) -> Result<ExpressionResult> {
+    check_arity("sleep", arguments, 1)?;
 
     if let Value::Int(i) = arguments[0] {
         if i < 0 {
             bail!("Negative duration")
         }
 
-        thread::sleep(time::Duration::from_secs(i as u64));
-        Ok(Value::Bool(true))
+        context.io.sleep(Duration::from_secs(i as u64))?;
+        Ok(ExpressionResult::Value(Value::Bool(true)))
     } else {
-        Err(anyhow!("Type error"))
+        bail!(RundownError::InvalidArgument {
+            name: "sleep".to_owned(),
+            expected: "an int",
+            actual: arguments[0].type_name(),
+        })
+    }
+}
+
+fn len(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("len", arguments, 1)?;
+
+    let length = match &arguments[0] {
+        Value::Str(s) => s.chars().count(),
+        Value::List(l) => l.len(),
+        Value::Map(m) => m.len(),
+        v => bail!(RundownError::InvalidArgument {
+            name: "len".to_owned(),
+            expected: "a string, list or map",
+            actual: v.type_name(),
+        }),
+    };
+    Ok(Value::Int(length as i64))
+}
+
+/// Values are never shared, so rather than modifying the list in place this returns a new list
+fn push(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("push", arguments, 2)?;
+
+    match &arguments[0] {
+        Value::List(l) => {
+            let mut l = l.clone();
+            l.push(arguments[1].clone());
+            Ok(Value::List(l))
+        }
+        v => bail!(RundownError::InvalidArgument {
+            name: "push".to_owned(),
+            expected: "a list",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+fn keys(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("keys", arguments, 1)?;
+
+    match &arguments[0] {
+        Value::Map(m) => Ok(Value::List(m.keys().cloned().collect())),
+        v => bail!(RundownError::InvalidArgument {
+            name: "keys".to_owned(),
+            expected: "a map",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+/// Convert a float to an int, failing rather than saturating if it is out of range
+fn float_to_int(name: &str, f: f64) -> Result<i64> {
+    if !f.is_finite() || f < i64::MIN as f64 || f >= i64::MAX as f64 {
+        bail!("{} is out of range for {}", f, name);
+    }
+
+    Ok(f as i64)
+}
+
+/// Floats are truncated towards zero, and strings are parsed
+fn int(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("int", arguments, 1)?;
+
+    match &arguments[0] {
+        Value::Int(i) => Ok(Value::Int(*i)),
+        Value::BigInt(n) => Ok(Value::BigInt(n.clone())),
+        Value::Float(f) => Ok(Value::Int(float_to_int("int", f.trunc())?)),
+        Value::Bool(b) => Ok(Value::Int(*b as i64)),
+        Value::Str(s) => match s.trim().parse::<i64>() {
+            Ok(i) => Ok(Value::Int(i)),
+            Err(_) => bail!("Cannot convert \"{}\" to an int", s),
+        },
+        v => bail!(RundownError::InvalidArgument {
+            name: "int".to_owned(),
+            expected: "a number, bool or string",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+fn float(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("float", arguments, 1)?;
+
+    match &arguments[0] {
+        Value::Int(i) => Ok(Value::Float(*i as f64)),
+        Value::BigInt(n) => Ok(Value::Float(n.to_f64().unwrap_or(f64::NAN))),
+        Value::Float(f) => Ok(Value::Float(*f)),
+        Value::Str(s) => match s.trim().parse::<f64>() {
+            Ok(f) => Ok(Value::Float(f)),
+            Err(_) => bail!("Cannot convert \"{}\" to a float", s),
+        },
+        v => bail!(RundownError::InvalidArgument {
+            name: "float".to_owned(),
+            expected: "a number or string",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+/// `round(x)` rounds to the nearest int, and `round(x, places)` rounds to a float with that many
+/// decimal places
+fn round(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    if arguments.len() != 1 && arguments.len() != 2 {
+        check_arity("round", arguments, 1)?;
+    }
+
+    let x = match &arguments[0] {
+        Value::Int(_) | Value::BigInt(_) if arguments.len() == 1 => {
+            return Ok(arguments[0].clone());
+        }
+        Value::Int(i) => *i as f64,
+        Value::BigInt(n) => n.to_f64().unwrap_or(f64::NAN),
+        Value::Float(f) => *f,
+        v => bail!(RundownError::InvalidArgument {
+            name: "round".to_owned(),
+            expected: "a number",
+            actual: v.type_name(),
+        }),
+    };
+
+    match arguments.get(1) {
+        None => Ok(Value::Int(float_to_int("round", x.round())?)),
+        Some(Value::Int(places)) => {
+            let scale = 10f64.powi((*places).clamp(-308, 308) as i32);
+            Ok(Value::Float((x * scale).round() / scale))
+        }
+        Some(v) => bail!(RundownError::InvalidArgument {
+            name: "round".to_owned(),
+            expected: "an int number of decimal places",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+fn str(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("str", arguments, 1)?;
+
+    Ok(Value::Str(arguments[0].to_string()))
+}
+
+fn upper(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("upper", arguments, 1)?;
+    Ok(Value::Str(
+        string_argument("upper", arguments, 0)?.to_uppercase(),
+    ))
+}
+
+fn lower(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("lower", arguments, 1)?;
+    Ok(Value::Str(
+        string_argument("lower", arguments, 0)?.to_lowercase(),
+    ))
+}
+
+fn trim(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("trim", arguments, 1)?;
+    Ok(Value::Str(
+        string_argument("trim", arguments, 0)?.trim().to_owned(),
+    ))
+}
+
+fn contains(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("contains", arguments, 2)?;
+    let s = string_argument("contains", arguments, 0)?;
+    let pattern = string_argument("contains", arguments, 1)?;
+    Ok(Value::Bool(s.contains(pattern)))
+}
+
+fn starts_with(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("starts_with", arguments, 2)?;
+    let s = string_argument("starts_with", arguments, 0)?;
+    let prefix = string_argument("starts_with", arguments, 1)?;
+    Ok(Value::Bool(s.starts_with(prefix)))
+}
+
+fn ends_with(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("ends_with", arguments, 2)?;
+    let s = string_argument("ends_with", arguments, 0)?;
+    let suffix = string_argument("ends_with", arguments, 1)?;
+    Ok(Value::Bool(s.ends_with(suffix)))
+}
+
+/// Splitting on an empty separator splits a string into its characters
+fn split(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("split", arguments, 2)?;
+    let s = string_argument("split", arguments, 0)?;
+    let separator = string_argument("split", arguments, 1)?;
+
+    let parts = if separator.is_empty() {
+        s.chars().map(|c| Value::Str(c.to_string())).collect()
+    } else {
+        s.split(separator)
+            .map(|part| Value::Str(part.to_owned()))
+            .collect()
+    };
+    Ok(Value::List(parts))
+}
+
+fn join(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("join", arguments, 2)?;
+    let separator = string_argument("join", arguments, 1)?;
+
+    match &arguments[0] {
+        Value::List(l) => Ok(Value::Str(
+            l.iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(separator),
+        )),
+        v => bail!(RundownError::InvalidArgument {
+            name: "join".to_owned(),
+            expected: "a list",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+fn replace(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("replace", arguments, 3)?;
+    let s = string_argument("replace", arguments, 0)?;
+    let from = string_argument("replace", arguments, 1)?;
+    let to = string_argument("replace", arguments, 2)?;
+    Ok(Value::Str(s.replace(from, to)))
+}
+
+/// `substr(s, start, length)` counts in characters, and stops early at the end of the string
+fn substr(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("substr", arguments, 3)?;
+    let s = string_argument("substr", arguments, 0)?;
+    let start = int_argument("substr", arguments, 1)?;
+    let length = int_argument("substr", arguments, 2)?;
+
+    let chars = s.chars().count();
+    if start < 0 || start as usize > chars {
+        bail!(RundownError::IndexOutOfBounds {
+            index: start,
+            length: chars,
+        });
+    }
+    if length < 0 {
+        bail!("Negative length {}", length);
+    }
+
+    Ok(Value::Str(
+        s.chars()
+            .skip(start as usize)
+            .take(length as usize)
+            .collect(),
+    ))
+}
+
+fn repeat(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("repeat", arguments, 2)?;
+    let s = string_argument("repeat", arguments, 0)?;
+    let count = int_argument("repeat", arguments, 1)?;
+
+    if count < 0 {
+        bail!(RundownError::NegativeRepeatCount(count));
+    }
+    if s.len().checked_mul(count as usize).is_none() {
+        bail!("Repeating a string {} times is too large", count);
+    }
+    Ok(Value::Str(s.repeat(count as usize)))
+}
+
+/// The position in characters of the first occurrence of a substring, or -1 if there is none
+fn index_of(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("index_of", arguments, 2)?;
+    let s = string_argument("index_of", arguments, 0)?;
+    let pattern = string_argument("index_of", arguments, 1)?;
+
+    let index = match s.find(pattern) {
+        Some(byte_index) => s[..byte_index].chars().count() as i64,
+        None => -1,
+    };
+    Ok(Value::Int(index))
+}
+
+/// `format("{} has {} gold", name, gold)` replaces each `{}` with the next argument, and `{{` and
+/// `}}` with literal braces
+fn format(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    if arguments.is_empty() {
+        check_arity("format", arguments, 1)?;
+    }
+    let template = string_argument("format", arguments, 0)?;
+
+    let mut result = String::new();
+    let mut values = arguments[1..].iter();
+    let mut placeholders = 0;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                result.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                placeholders += 1;
+                if let Some(value) = values.next() {
+                    result.push_str(&value.to_string());
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+
+    if placeholders != arguments.len() - 1 {
+        bail!(
+            "Format string has {} placeholder(s), but was given {} argument(s)",
+            placeholders,
+            arguments.len() - 1
+        );
+    }
+    Ok(Value::Str(result))
+}
+
+/// `random(lo, hi)` picks an int between lo and hi inclusive, like rolling a die with `random(1, 6)`.
+/// If either bound is a float, it picks a float which is at least lo and less than hi.
+fn random(context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("random", arguments, 2)?;
+
+    if let (Value::Int(lo), Value::Int(hi)) = (&arguments[0], &arguments[1]) {
+        if lo > hi {
+            bail!("Cannot pick a number between {} and {}", lo, hi);
+        }
+        return Ok(Value::Int(context.rng.gen_range(*lo..=*hi)));
+    }
+
+    let lo = number_argument("random", arguments, 0)?;
+    let hi = number_argument("random", arguments, 1)?;
+    if !(lo < hi && (hi - lo).is_finite()) {
+        bail!("Cannot pick a number between {} and {}", lo, hi);
+    }
+    Ok(Value::Float(context.rng.gen_range(lo..hi)))
+}
+
+fn choice(context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("choice", arguments, 1)?;
+
+    match list_argument("choice", arguments, 0)?.choose(&mut context.rng) {
+        Some(v) => Ok(v.clone()),
+        None => bail!("Cannot choose from an empty list"),
+    }
+}
+
+/// Like `push`, this returns a new list rather than shuffling the list in place
+fn shuffle(context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("shuffle", arguments, 1)?;
+
+    let mut l = list_argument("shuffle", arguments, 0)?.to_vec();
+    l.shuffle(&mut context.rng);
+    Ok(Value::List(l))
+}
+
+fn abs(context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("abs", arguments, 1)?;
+
+    match &arguments[0] {
+        Value::Int(i) => match i.checked_abs() {
+            Some(i) => Ok(Value::Int(i)),
+            None if context.big_integers => Ok(Value::from_big(BigInt::from(*i).abs())),
+            None => bail!(RundownError::Overflow(format!("abs({})", i))),
+        },
+        Value::BigInt(n) => Ok(Value::from_big(n.abs())),
+        Value::Float(f) => Ok(Value::Float(f.abs())),
+        v => bail!(RundownError::InvalidArgument {
+            name: "abs".to_owned(),
+            expected: "a number",
+            actual: v.type_name(),
+        }),
+    }
+}
+
+/// The numbers compared by `min` and `max`, which are either the arguments or a single list
+fn numbers<'a>(name: &str, arguments: &'a [Value]) -> Result<&'a [Value]> {
+    let numbers = match arguments {
+        [Value::List(l)] => l.as_slice(),
+        _ => arguments,
+    };
+    if numbers.is_empty() {
+        bail!("Cannot find the {} of no numbers", name);
+    }
+    for n in numbers {
+        if !matches!(n, Value::Int(_) | Value::BigInt(_) | Value::Float(_)) {
+            bail!(RundownError::InvalidArgument {
+                name: name.to_owned(),
+                expected: "numbers",
+                actual: n.type_name(),
+            });
+        }
+    }
+
+    Ok(numbers)
+}
+
+fn min(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    Ok(numbers("min", arguments)?.iter().min().unwrap().clone())
+}
+
+fn max(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    Ok(numbers("max", arguments)?.iter().max().unwrap().clone())
+}
+
+/// The most bits a big integer from `pow` can have, so that a huge exponent is an error rather than
+/// using up all the memory
+const MAX_POW_BITS: u64 = 1 << 20;
+
+/// Ints raised to a non-negative int power stay ints, anything else is a float
+fn pow(context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("pow", arguments, 2)?;
+
+    let (base, exponent) = match (&arguments[0], &arguments[1]) {
+        (Value::Int(base), Value::Int(exponent)) if *exponent >= 0 => (*base, *exponent),
+        _ => {
+            let base = number_argument("pow", arguments, 0)?;
+            let exponent = number_argument("pow", arguments, 1)?;
+            return Ok(Value::Float(base.powf(exponent)));
+        }
+    };
+
+    // These never grow, however large the exponent is
+    match base {
+        0 if exponent > 0 => return Ok(Value::Int(0)),
+        0 | 1 => return Ok(Value::Int(1)),
+        -1 => return Ok(Value::Int(if exponent % 2 == 0 { 1 } else { -1 })),
+        _ => (),
+    }
+
+    let overflow = || RundownError::Overflow(format!("pow({}, {})", base, exponent));
+    let result = u32::try_from(exponent)
+        .ok()
+        .and_then(|exponent| base.checked_pow(exponent));
+    match result {
+        Some(i) => Ok(Value::Int(i)),
+        None if context.big_integers => {
+            let base = BigInt::from(base);
+            // Each multiplication by the base adds at least this many bits
+            let bits = (base.bits() - 1).saturating_mul(exponent as u64);
+            if bits > MAX_POW_BITS {
+                bail!(
+                    "pow({}, {}) would have more than {} bits",
+                    base,
+                    exponent,
+                    MAX_POW_BITS
+                );
+            }
+            Ok(Value::from_big(base.pow(exponent as u32)))
+        }
+        None => bail!(overflow()),
+    }
+}
+
+fn clamp(_context: &mut Context, arguments: &[Value]) -> Result<Value> {
+    check_arity("clamp", arguments, 3)?;
+    numbers("clamp", arguments)?;
+
+    let (x, lo, hi) = (&arguments[0], &arguments[1], &arguments[2]);
+    if lo > hi {
+        bail!("Cannot clamp between {} and {}", lo, hi);
     }
+    Ok(x.clone().clamp(lo.clone(), hi.clone()))
 }
@@ -0,0 +1,201 @@
+use std::path::Path;
+
+use markdown::Block;
+
+use crate::ast::{self, ExpressionKind, Span, Statement, StatementKind};
+use crate::diagnostic::{annotate, render, Location};
+use crate::error::RundownError;
+use crate::markdown::{
+    condition_marker, is_menu_marker, ConditionMarker, Index, RUNDOWN_CODE_BLOCK_SYNTAX,
+};
+use crate::menu::Menu;
+
+/// Parse every rundown code block in the document without running it, returning each block
+/// which fails to parse, goto and menu option for a section which does not exist, `<!-- menu -->`
+/// without a menu, and `<!-- if -->` marker which is invalid or not closed, rendered along with
+/// where it is
+pub fn check(path: &Path, source: &str, section_index: &Index) -> Vec<String> {
+    let mut problems = vec![];
+
+    for (name, section) in section_index {
+        let mut code_blocks = 0;
+        let mut open_conditions = vec![];
+        // Whether the block before was `<!-- menu -->`
+        let mut menu_next = false;
+        let located =
+            |message: String| format!("{}\n --> {} (section `{}`)", message, path.display(), name);
+        for section_block in section {
+            match condition_marker(&section_block.block) {
+                Some(ConditionMarker::If(condition)) => {
+                    if let Err(e) = ast::parse_expression(&condition, 1) {
+                        problems.push(located(format!("Invalid condition `{}`: {}", condition, e)));
+                    }
+                    open_conditions.push(condition);
+                    continue;
+                }
+                Some(ConditionMarker::Else) => {
+                    if open_conditions.is_empty() {
+                        problems.push(located(
+                            RundownError::UnmatchedConditionMarker("else").to_string(),
+                        ));
+                    }
+                    continue;
+                }
+                Some(ConditionMarker::EndIf) => {
+                    if open_conditions.pop().is_none() {
+                        problems.push(located(
+                            RundownError::UnmatchedConditionMarker("endif").to_string(),
+                        ));
+                    }
+                    continue;
+                }
+                None => (),
+            }
+            if is_menu_marker(&section_block.block) {
+                menu_next = true;
+                continue;
+            }
+
+            if std::mem::take(&mut menu_next) {
+                match Menu::from_block(&section_block.block) {
+                    Some(menu) => {
+                        for target in menu.sections() {
+                            if !section_index.contains_key(target) {
+                                problems.push(located(format!(
+                                    "Menu option for section \"{}\" which does not exist",
+                                    target
+                                )));
+                            }
+                        }
+                    }
+                    None => problems.push(located(
+                        "`<!-- menu -->` is not followed by a list of links to sections".to_owned(),
+                    )),
+                }
+            }
+
+            let content = match &section_block.block {
+                Block::CodeBlock(Some(syntax), content) if syntax == RUNDOWN_CODE_BLOCK_SYNTAX => {
+                    content
+                }
+                _ => continue,
+            };
+            code_blocks += 1;
+            let location = Location {
+                path,
+                section: name,
+                block: code_blocks,
+            };
+
+            let statements = match ast::parse(content, section_block.line.unwrap_or(1)) {
+                Ok(statements) => statements,
+                Err(e) => {
+                    problems.push(annotate(e, source, &location).to_string());
+                    continue;
+                }
+            };
+
+            let mut labels = vec![];
+            collect_goto_labels(&statements, &mut labels);
+            for (label, span) in labels {
+                if !section_index.contains_key(label) {
+                    let message =
+                        format!("Goto or call to section \"{}\" which does not exist", label);
+                    problems.push(render(source, &location, span, &message));
+                }
+            }
+        }
+        for condition in open_conditions {
+            problems.push(located(
+                RundownError::UnclosedCondition(condition).to_string(),
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Collect the labels of all gotos and calls with a literal string target. Targets computed at runtime cannot
+/// be checked statically.
+fn collect_goto_labels<'a>(statements: &'a [Statement], labels: &mut Vec<(&'a str, Span)>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Goto(expression) | StatementKind::Call(expression) => {
+                if let ExpressionKind::Str(label) = &expression.kind {
+                    labels.push((label, statement.span));
+                }
+            }
+            StatementKind::If {
+                statements,
+                else_statements,
+                ..
+            } => {
+                collect_goto_labels(statements, labels);
+                if let Some(else_statements) = else_statements {
+                    collect_goto_labels(else_statements, labels);
+                }
+            }
+            StatementKind::Match { arms, .. } => {
+                for arm in arms {
+                    collect_goto_labels(&arm.statements, labels);
+                }
+            }
+            StatementKind::While { statements, .. }
+            | StatementKind::For { statements, .. }
+            | StatementKind::FunctionDefinition { statements, .. } => {
+                collect_goto_labels(statements, labels);
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::document::Document;
+
+    fn problems_in(source: &str) -> Vec<String> {
+        Document::parse(source).check()
+    }
+
+    #[test]
+    fn clean_documents_have_no_problems() {
+        let source = "# Start\n\n```rundown\nif (read()) { goto \"end\"; }\ncall \"end\";\n```\n\n<!-- menu -->\n\n1. [Onwards](#end)\n\n- [Contents](#nowhere)\n\n<!-- if true -->\n\nShown\n\n<!-- endif -->\n\n# End\n\nBye\n";
+        assert!(problems_in(source).is_empty());
+    }
+
+    #[test]
+    fn missing_labels_are_reported() {
+        let source = "# Start\n\n```rundown\nif (read()) { goto \"nowhere\"; }\ncall \"end\";\n```\n\n<!-- menu -->\n\n1. [Lost](#lost)\n\n<!-- menu -->\n\nNot a menu\n\n# End\n";
+        let problems = problems_in(source);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[1].starts_with(
+            "Menu option for section \"lost\" which does not exist\n --> <document> (section `start`)"
+        ));
+        assert!(problems[0].starts_with(
+            "Goto or call to section \"nowhere\" which does not exist\n --> <document>:4:15 (section `start`, code block 1)"
+        ));
+        assert!(problems[2]
+            .starts_with("`<!-- menu -->` is not followed by a list of links to sections"));
+    }
+
+    #[test]
+    fn unparsable_blocks_are_reported() {
+        let source = "# Start\n\n```rundown\nlet a = 1;\n```\n\n```rundown\nlet a = ;\n```\n";
+        let problems = problems_in(source);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains(" --> <document>:8:9 (section `start`, code block 2)"));
+    }
+
+    #[test]
+    fn condition_markers_are_checked() {
+        let source =
+            "# Start\n\n<!-- else -->\n\n<!-- if a + -->\n\nText\n\n# End\n\n<!-- endif -->\n";
+        let problems = problems_in(source);
+        assert_eq!(problems.len(), 4);
+        assert!(problems[0].starts_with("`<!-- else -->` without a matching `<!-- if -->`"));
+        assert!(problems[1].starts_with("Invalid condition `a +`"));
+        assert!(problems[2].starts_with("`<!-- if a + -->` is missing an `<!-- endif -->`"));
+        assert!(problems[3].starts_with("`<!-- endif -->` without a matching `<!-- if -->`"));
+    }
+}
@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+
+use crate::ast::{Span, SyntaxError};
+use crate::error::RundownError;
+
+/// The code block which was being parsed or run when an error occurred
+pub struct Location<'a> {
+    pub path: &'a Path,
+    pub section: &'a str,
+    /// 1-indexed position of the code block amongst the rundown code blocks of its section
+    pub block: usize,
+}
+
+/// The line of the document an error points at, if it carries a span
+pub fn error_line(error: &Error) -> Option<usize> {
+    match error.downcast_ref::<SyntaxError>() {
+        Some(e) => Some(e.span.line),
+        None => error.downcast_ref::<Span>().map(|span| span.line),
+    }
+}
+
+/// If an error carries a span, replace it with one which points at the offending line of source.
+/// Syntax errors and `RundownError`s are kept underneath, so they can still be downcast to.
+pub fn annotate(error: Error, source: &str, location: &Location) -> Error {
+    let (span, message) = if let Some(e) = error.downcast_ref::<SyntaxError>() {
+        (e.span, e.message.clone())
+    } else if let Some(span) = error.downcast_ref::<Span>() {
+        (*span, error.root_cause().to_string())
+    } else {
+        return error;
+    };
+
+    let rendered = render(source, location, span, &message);
+    match error.downcast::<SyntaxError>() {
+        Ok(e) => Error::new(e).context(rendered),
+        Err(error) => match error.downcast::<RundownError>() {
+            Ok(e) => Error::new(e).context(rendered),
+            Err(_) => anyhow!(rendered),
+        },
+    }
+}
+
+/// Render a message along with the line of source it refers to and a caret underlining the span
+pub fn render(source: &str, location: &Location, span: Span, message: &str) -> String {
+    let line = source
+        .lines()
+        .nth(span.line.saturating_sub(1))
+        .unwrap_or("");
+    let line_number = span.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+
+    let end_column = if span.end_line == span.line {
+        span.end_column
+    } else {
+        line.chars().count() + 1
+    };
+    let underline = "^".repeat(end_column.saturating_sub(span.column).max(1));
+    let padding = " ".repeat(span.column.saturating_sub(1));
+
+    format!(
+        "{}\n{}--> {}:{}:{} (section `{}`, code block {})\n{} |\n{} | {}\n{} | {}{}",
+        message,
+        gutter,
+        location.path.display(),
+        span.line,
+        span.column,
+        location.section,
+        location.block,
+        gutter,
+        line_number,
+        line,
+        gutter,
+        padding,
+        underline,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ast;
+
+    const SOURCE: &str = "# Shop\n\n```rundown\nlet gold = 1;\n```\n\n```rundown\nlet price = 2;\nlet left = 1 -\n    price * \"x\";\n```\n";
+
+    fn location() -> Location<'static> {
+        Location {
+            path: Path::new("story.md"),
+            section: "shop",
+            block: 2,
+        }
+    }
+
+    #[test]
+    fn render_underlines_the_span() {
+        let span = Span {
+            line: 9,
+            column: 12,
+            end_line: 9,
+            end_column: 15,
+        };
+        assert_eq!(
+            render(SOURCE, &location(), span, "Oops"),
+            "Oops\n --> story.md:9:12 (section `shop`, code block 2)\n  |\n9 | let left = 1 -\n  |            ^^^"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_point_at_their_line_in_the_document() {
+        let error = ast::parse("let price = 2;\nlet left = ;", 8).unwrap_err();
+        let rendered = annotate(error, SOURCE, &location()).to_string();
+        assert!(rendered.starts_with("Syntax error"));
+        assert!(rendered.contains("\n --> story.md:9:12 (section `shop`, code block 2)\n"));
+    }
+
+    #[test]
+    fn runtime_errors_point_at_their_line_in_the_document() {
+        let statements =
+            ast::parse("let price = 2;\nlet left = 1 -\n    price * \"x\";", 8).unwrap();
+        let error = crate::eval::Context::new(Default::default())
+            .eval(&statements)
+            .unwrap_err();
+        let rendered = annotate(error, SOURCE, &location()).to_string();
+        assert_eq!(
+            rendered,
+            "Type error, cannot apply `*` to int and string\n  --> story.md:10:5 (section `shop`, code block 2)\n   |\n10 |     price * \"x\";\n   |     ^^^^^^^^^^^"
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::check::check;
+use crate::markdown::{construct_index, Index};
+
+/// A rundown story, split into the Markdown blocks of each of its sections
+#[derive(Debug, Clone)]
+pub struct Document {
+    /// Path used when reporting errors
+    pub(crate) path: PathBuf,
+    pub(crate) source: String,
+    pub(crate) sections: Index,
+}
+
+impl Document {
+    pub fn parse(source: &str) -> Self {
+        Self {
+            path: PathBuf::from("<document>"),
+            source: source.to_owned(),
+            sections: construct_index(source),
+        }
+    }
+
+    /// Read and parse a file, referring to it by its path in error messages
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let source = fs::read_to_string(path.as_ref())?;
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+            ..Self::parse(&source)
+        })
+    }
+
+    /// The names of the sections, in the order they appear, which can be used as goto labels
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections.keys().map(String::as_str)
+    }
+
+    /// Parse every rundown code block without running it, returning the problems found, such as
+    /// blocks which fail to parse and gotos to sections which do not exist
+    pub fn check(&self) -> Vec<String> {
+        check(&self.path, &self.source, &self.sections)
+    }
+}
@@ -0,0 +1,78 @@
+use thiserror::Error;
+
+/// Errors raised while running a rundown script. These are returned wrapped in an `anyhow::Error`,
+/// and can be recovered with `error.downcast_ref::<RundownError>()`.
+#[derive(Debug, Error)]
+pub enum RundownError {
+    #[error("Type error, cannot apply `{operator}` to {left} and {right}")]
+    TypeMismatch {
+        operator: String,
+        left: &'static str,
+        right: &'static str,
+    },
+    #[error("Type error, cannot apply `{operator}` to {operand}")]
+    InvalidOperand {
+        operator: String,
+        operand: &'static str,
+    },
+    #[error("Attempted to access an undeclared variable `{0}`")]
+    UndeclaredVariable(String),
+    #[error("Attempted to redefine local variable `{0}`")]
+    RedefinedVariable(String),
+    #[error("Attempted to define static variable `{0}` outside of a function context")]
+    StaticOutsideFunction(String),
+    #[error("Attempted to call undefined function `{0}`")]
+    UndefinedFunction(String),
+    #[error("Attempted to define function `{0}` within a function context")]
+    NestedFunction(String),
+    #[error("Function `{name}` expects {expected} argument(s), but was given {actual}")]
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Function `{name}` expects {expected}, but was given {actual}")]
+    InvalidArgument {
+        name: String,
+        expected: &'static str,
+        actual: &'static str,
+    },
+    #[error("Attempted to jump to a label of type {0}, labels must be strings")]
+    InvalidLabel(&'static str),
+    #[error("Tried to goto section `{0}` which does not exist")]
+    UnknownLabel(String),
+    #[error("Cannot iterate over {0}")]
+    NotIterable(&'static str),
+    #[error("Attempted to break outside of a loop")]
+    BreakOutsideLoop,
+    #[error("Attempted to continue outside of a loop")]
+    ContinueOutsideLoop,
+    #[error("Index {index} is out of bounds for length {length}")]
+    IndexOutOfBounds { index: i64, length: usize },
+    #[error("Key {0} not found in map")]
+    KeyNotFound(String),
+    #[error("Division by zero in `{0}`")]
+    DivisionByZero(String),
+    #[error("Integer overflow in `{0}`, run with --big-integers for arbitrary precision")]
+    Overflow(String),
+    #[error("Negative repeat count {0}")]
+    NegativeRepeatCount(i64),
+    #[error("Attempted to call or return from a section within function `{0}`, this can only be done directly in a code block")]
+    SectionCallInFunction(String),
+    #[error("Attempted to return_section from a section which was not called")]
+    ReturnSectionOutsideCall,
+    #[error("Section calls are nested more than {0} deep, check for a section which calls itself")]
+    CallDepthExceeded(usize),
+    #[error("Attempted to goto `{0}` from a template, templates can only display values")]
+    GotoInTemplate(String),
+    #[error("`<!-- {0} -->` without a matching `<!-- if -->`")]
+    UnmatchedConditionMarker(&'static str),
+    #[error("`<!-- if {0} -->` is missing an `<!-- endif -->` before the end of the section")]
+    UnclosedCondition(String),
+    #[error("Save file {0} was made from a different version of this story")]
+    StaleSave(String),
+    #[error("Save file {path} has unsupported version {version}")]
+    UnsupportedSaveVersion { path: String, version: String },
+    #[error("Builtin `{name}` failed: {message}")]
+    Builtin { name: String, message: String },
+}
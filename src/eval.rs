@@ -1,95 +1,345 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Error, Result};
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 
-use crate::ast::{BinaryOperator, Expression, ScopeSpecifier, Statement, UnaryOperator};
+use crate::ast::{
+    BinaryOperator, Expression, ExpressionKind, Iterable, Pattern, ScopeSpecifier, Span, Statement,
+    StatementKind, StringPart, UnaryOperator,
+};
+use crate::error::RundownError;
+use crate::interpreter::Position;
+use crate::io::{Io, Terminal};
+use crate::save::Checkpoint;
 
 pub type Scope = HashMap<String, Value>;
-pub type Builtin = fn(&[Value]) -> Result<Value>;
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// A function provided by the host rather than defined in a script. Builtins get mutable access to
+/// the interpreter, so they can hold state, read and write variables, or jump to another section by
+/// returning a goto. Any closure with the right signature is a builtin.
+pub trait Builtin {
+    fn call(&mut self, context: &mut Context, arguments: &[Value]) -> Result<ExpressionResult>;
+}
+
+impl<F> Builtin for F
+where
+    F: FnMut(&mut Context, &[Value]) -> Result<ExpressionResult>,
+{
+    fn call(&mut self, context: &mut Context, arguments: &[Value]) -> Result<ExpressionResult> {
+        self(context, arguments)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Value {
     Str(String),
     Int(i64),
+    /// An int which does not fit in 64 bits, only produced in big integer mode
+    BigInt(BigInt),
+    Float(f64),
     Bool(bool),
+    List(Vec<Value>),
+    Map(BTreeMap<Value, Value>),
 }
 
 impl Value {
-    fn as_bool(&self) -> bool {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::Int(_) | Value::BigInt(_) => "int",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+
+    /// Patterns match the values which are `==` to them, so `1` matches `1.0` too
+    fn matches(&self, pattern: &Pattern) -> bool {
+        let value = match pattern {
+            Pattern::Wildcard => return true,
+            Pattern::Str(s) => Value::Str(s.clone()),
+            Pattern::Int(i) => Value::Int(*i),
+            Pattern::Bool(b) => Value::Bool(*b),
+        };
+        *self == value
+    }
+
+    pub(crate) fn as_bool(&self) -> bool {
         match self {
             Value::Str(s) => !s.is_empty(),
             Value::Int(i) => *i != 0,
+            Value::BigInt(n) => !n.is_zero(),
+            Value::Float(f) => *f != 0.0,
             Value::Bool(b) => *b,
+            Value::List(l) => !l.is_empty(),
+            Value::Map(m) => !m.is_empty(),
         }
     }
 
-    fn add(&self, other: Value) -> Result<Value> {
+    /// The values a `for` loop iterates over: the characters of a string, the elements of a list,
+    /// or the keys of a map
+    fn elements(&self) -> Result<Vec<Value>> {
         match self {
-            Value::Str(s) => Ok(Value::Str(format!("{}{}", s, other.to_string()))),
-            Value::Int(i) => {
-                if let Value::Int(other) = other {
-                    Ok(Value::Int(i + other))
-                } else {
-                    Err(anyhow!("Type error"))
-                }
+            Value::Str(s) => Ok(s.chars().map(|c| Value::Str(c.to_string())).collect()),
+            Value::List(l) => Ok(l.clone()),
+            Value::Map(m) => Ok(m.keys().cloned().collect()),
+            _ => Err(RundownError::NotIterable(self.type_name()).into()),
+        }
+    }
+
+    fn list_position(index: i64, length: usize) -> Result<usize> {
+        if index < 0 || index as usize >= length {
+            bail!(RundownError::IndexOutOfBounds { index, length });
+        }
+
+        Ok(index as usize)
+    }
+
+    fn index_mismatch(&self, index: &Value) -> Error {
+        RundownError::TypeMismatch {
+            operator: "[]".to_owned(),
+            left: self.type_name(),
+            right: index.type_name(),
+        }
+        .into()
+    }
+
+    fn index(&self, index: &Value) -> Result<Value> {
+        match (self, index) {
+            (Value::Str(s), Value::Int(i)) => {
+                let position = Value::list_position(*i, s.chars().count())?;
+                Ok(Value::Str(s.chars().nth(position).unwrap().to_string()))
             }
-            _ => Err(anyhow!("Type error")),
+            (Value::List(l), Value::Int(i)) => Ok(l[Value::list_position(*i, l.len())?].clone()),
+            (Value::Map(m), key) => m
+                .get(key)
+                .cloned()
+                .ok_or_else(|| RundownError::KeyNotFound(key.to_string()).into()),
+            _ => Err(self.index_mismatch(index)),
         }
     }
 
-    fn subtract(&self, other: Value) -> Result<Value> {
-        if let Value::Int(a) = self {
-            if let Value::Int(b) = other {
-                return Ok(Value::Int(a - b));
+    fn index_mut(&mut self, index: &Value) -> Result<&mut Value> {
+        match (self, index) {
+            (Value::List(l), Value::Int(i)) => {
+                let position = Value::list_position(*i, l.len())?;
+                Ok(&mut l[position])
             }
+            (Value::Map(m), key) => m
+                .get_mut(key)
+                .ok_or_else(|| RundownError::KeyNotFound(key.to_string()).into()),
+            (target, _) => Err(target.index_mismatch(index)),
         }
+    }
 
-        Err(anyhow!("Type error"))
+    /// Replace an element of a list, or insert an entry into a map
+    fn set_index(&mut self, index: Value, value: Value) -> Result<()> {
+        match (self, index) {
+            (Value::List(l), Value::Int(i)) => {
+                let position = Value::list_position(i, l.len())?;
+                l[position] = value;
+            }
+            (Value::Map(m), key) => {
+                m.insert(key, value);
+            }
+            (target, index) => return Err(target.index_mismatch(&index)),
+        }
+
+        Ok(())
     }
 
-    fn multiply(&self, other: Value) -> Result<Value> {
+    fn contains(&self, element: &Value) -> Result<bool> {
+        match (self, element) {
+            (Value::Str(s), Value::Str(substring)) => Ok(s.contains(substring.as_str())),
+            (Value::List(l), element) => Ok(l.contains(element)),
+            (Value::Map(m), key) => Ok(m.contains_key(key)),
+            _ => Err(element.type_mismatch(BinaryOperator::In, self)),
+        }
+    }
+
+    fn type_mismatch(&self, operator: BinaryOperator, other: &Value) -> Error {
+        RundownError::TypeMismatch {
+            operator: operator.to_string(),
+            left: self.type_name(),
+            right: other.type_name(),
+        }
+        .into()
+    }
+
+    fn overflow(&self, operator: BinaryOperator, other: &Value) -> Error {
+        RundownError::Overflow(format!("{} {} {}", self, operator, other)).into()
+    }
+
+    fn division_by_zero(&self, operator: BinaryOperator, other: &Value) -> Error {
+        RundownError::DivisionByZero(format!("{} {} {}", self, operator, other)).into()
+    }
+
+    /// Big integers are only kept for values which do not fit in an int
+    pub fn from_big(n: BigInt) -> Value {
+        match n.to_i64() {
+            Some(i) => Value::Int(i),
+            None => Value::BigInt(n),
+        }
+    }
+
+    fn to_big(&self) -> Option<BigInt> {
         match self {
-            Value::Str(s) => {
-                if let Value::Int(other) = other {
-                    if other < 0 {
-                        Err(anyhow!("Negative repeat count"))
-                    } else {
-                        Ok(Value::Str(s.repeat(other as usize)))
-                    }
-                } else {
-                    Err(anyhow!("Type error"))
-                }
+            Value::Int(i) => Some(BigInt::from(*i)),
+            Value::BigInt(n) => Some(n.clone()),
+            _ => None,
+        }
+    }
+
+    fn to_float(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::BigInt(n) => Some(n.to_f64().unwrap_or(f64::NAN)),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    /// If either side of an arithmetic operator is a float and the other is a number, both sides
+    /// are promoted to floats
+    fn promote(&self, other: &Value) -> Option<(f64, f64)> {
+        match (self, other) {
+            (Value::Float(_), _) | (_, Value::Float(_)) => {
+                Some((self.to_float()?, other.to_float()?))
             }
-            Value::Int(i) => {
-                if let Value::Int(other) = other {
-                    Ok(Value::Int(i * other))
-                } else {
-                    Err(anyhow!("Type error"))
-                }
+            _ => None,
+        }
+    }
+
+    /// If either side of an arithmetic operator is a big integer and the other is an int, both
+    /// sides are promoted to big integers
+    fn promote_big(&self, other: &Value) -> Option<(BigInt, BigInt)> {
+        match (self, other) {
+            (Value::BigInt(_), _) | (_, Value::BigInt(_)) => {
+                Some((self.to_big()?, other.to_big()?))
+            }
+            _ => None,
+        }
+    }
+
+    fn add(&self, other: Value) -> Result<Value> {
+        if let Some((a, b)) = self.promote(&other) {
+            return Ok(Value::Float(a + b));
+        }
+        if let Some((a, b)) = self.promote_big(&other) {
+            return Ok(Value::from_big(a + b));
+        }
+
+        match (self, &other) {
+            (Value::Str(s), _) => Ok(Value::Str(format!("{}{}", s, other))),
+            (Value::Int(a), Value::Int(b)) => a
+                .checked_add(*b)
+                .map(Value::Int)
+                .ok_or_else(|| self.overflow(BinaryOperator::Add, &other)),
+            (Value::List(a), Value::List(b)) => {
+                Ok(Value::List(a.iter().chain(b.iter()).cloned().collect()))
             }
-            _ => Err(anyhow!("Type error")),
+            _ => Err(self.type_mismatch(BinaryOperator::Add, &other)),
+        }
+    }
+
+    fn subtract(&self, other: Value) -> Result<Value> {
+        if let Some((a, b)) = self.promote(&other) {
+            return Ok(Value::Float(a - b));
+        }
+        if let Some((a, b)) = self.promote_big(&other) {
+            return Ok(Value::from_big(a - b));
+        }
+
+        match (self, &other) {
+            (Value::Int(a), Value::Int(b)) => a
+                .checked_sub(*b)
+                .map(Value::Int)
+                .ok_or_else(|| self.overflow(BinaryOperator::Subtract, &other)),
+            _ => Err(self.type_mismatch(BinaryOperator::Subtract, &other)),
+        }
+    }
+
+    fn multiply(&self, other: Value) -> Result<Value> {
+        if let Some((a, b)) = self.promote(&other) {
+            return Ok(Value::Float(a * b));
+        }
+        if let Some((a, b)) = self.promote_big(&other) {
+            return Ok(Value::from_big(a * b));
+        }
+
+        match (self, &other) {
+            (Value::Str(_), Value::Int(b)) if *b < 0 => {
+                Err(RundownError::NegativeRepeatCount(*b).into())
+            }
+            (Value::Str(s), Value::Int(b)) => match s.len().checked_mul(*b as usize) {
+                Some(_) => Ok(Value::Str(s.repeat(*b as usize))),
+                None => Err(self.overflow(BinaryOperator::Multiply, &other)),
+            },
+            (Value::Int(a), Value::Int(b)) => a
+                .checked_mul(*b)
+                .map(Value::Int)
+                .ok_or_else(|| self.overflow(BinaryOperator::Multiply, &other)),
+            _ => Err(self.type_mismatch(BinaryOperator::Multiply, &other)),
         }
     }
 
     fn divide(&self, other: Value) -> Result<Value> {
-        if let Value::Int(a) = self {
-            if let Value::Int(b) = other {
-                return Ok(Value::Int(a / b));
+        if let Some((a, b)) = self.promote(&other) {
+            if b == 0.0 {
+                return Err(self.division_by_zero(BinaryOperator::Divide, &other));
+            }
+            return Ok(Value::Float(a / b));
+        }
+        if let Some((a, b)) = self.promote_big(&other) {
+            if b.is_zero() {
+                return Err(self.division_by_zero(BinaryOperator::Divide, &other));
             }
+            return Ok(Value::from_big(a / b));
         }
 
-        Err(anyhow!("Type error"))
+        match (self, &other) {
+            (Value::Int(_), Value::Int(0)) => {
+                Err(self.division_by_zero(BinaryOperator::Divide, &other))
+            }
+            // Only overflows for the most negative int divided by -1
+            (Value::Int(a), Value::Int(b)) => a
+                .checked_div(*b)
+                .map(Value::Int)
+                .ok_or_else(|| self.overflow(BinaryOperator::Divide, &other)),
+            _ => Err(self.type_mismatch(BinaryOperator::Divide, &other)),
+        }
     }
 
     fn modulo(&self, other: Value) -> Result<Value> {
-        if let Value::Int(a) = self {
-            if let Value::Int(b) = other {
-                return Ok(Value::Int(a % b));
+        if let Some((a, b)) = self.promote(&other) {
+            if b == 0.0 {
+                return Err(self.division_by_zero(BinaryOperator::Modulo, &other));
             }
+            return Ok(Value::Float(a % b));
+        }
+        if let Some((a, b)) = self.promote_big(&other) {
+            if b.is_zero() {
+                return Err(self.division_by_zero(BinaryOperator::Modulo, &other));
+            }
+            return Ok(Value::from_big(a % b));
         }
 
-        Err(anyhow!("Type error"))
+        match (self, &other) {
+            (Value::Int(_), Value::Int(0)) => {
+                Err(self.division_by_zero(BinaryOperator::Modulo, &other))
+            }
+            (Value::Int(a), Value::Int(b)) => a
+                .checked_rem(*b)
+                .map(Value::Int)
+                .ok_or_else(|| self.overflow(BinaryOperator::Modulo, &other)),
+            _ => Err(self.type_mismatch(BinaryOperator::Modulo, &other)),
+        }
     }
 
     fn and(&self, other: Value) -> Result<Value> {
@@ -105,11 +355,51 @@ impl Value {
     }
 
     fn negate(&self) -> Result<Value> {
-        if let Value::Int(i) = self {
-            return Ok(Value::Int(-i));
+        match self {
+            Value::Int(i) => i
+                .checked_neg()
+                .map(Value::Int)
+                .ok_or_else(|| RundownError::Overflow(format!("-{}", i)).into()),
+            Value::BigInt(n) => Ok(Value::from_big(-n)),
+            Value::Float(f) => Ok(Value::Float(-f)),
+            _ => Err(RundownError::InvalidOperand {
+                operator: UnaryOperator::Negate.to_string(),
+                operand: self.type_name(),
+            }
+            .into()),
+        }
+    }
+
+    /// In big integer mode ints are widened before arithmetic, so that it is done with arbitrary
+    /// precision rather than failing on overflow
+    fn widen(self) -> Value {
+        match self {
+            Value::Int(i) => Value::BigInt(BigInt::from(i)),
+            v => v,
+        }
+    }
+}
+
+impl Value {
+    /// Ints and floats are ordered amongst each other, other types are only ordered against values
+    /// of the same type
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Str(_) => 0,
+            Value::Int(_) | Value::BigInt(_) | Value::Float(_) => 1,
+            Value::Bool(_) => 2,
+            Value::List(_) => 3,
+            Value::Map(_) => 4,
         }
+    }
 
-        Err(anyhow!("Type error"))
+    /// Strings are quoted when they are inside a list or map, so that `["a, b"]` and `["a", "b"]`
+    /// are distinguishable
+    fn fmt_element(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{:?}", s),
+            _ => write!(f, "{}", self),
+        }
     }
 }
 
@@ -118,40 +408,140 @@ impl fmt::Display for Value {
         match self {
             Value::Str(s) => write!(f, "{}", s),
             Value::Int(i) => write!(f, "{}", i),
+            Value::BigInt(n) => write!(f, "{}", n),
+            // Always show a decimal point, so that floats can be told apart from ints
+            Value::Float(x) if x.is_finite() && x.fract() == 0.0 => write!(f, "{:.1}", x),
+            Value::Float(x) => write!(f, "{}", x),
             Value::Bool(b) => write!(f, "{}", b),
+            Value::List(l) => {
+                write!(f, "[")?;
+                for (i, element) in l.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    element.fmt_element(f)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(m) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in m.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    key.fmt_element(f)?;
+                    write!(f, ": ")?;
+                    value.fmt_element(f)?;
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
 
+/// Compare floats by value, falling back to a total order so that NaN can still be sorted and used
+/// as a map key
+fn compare_floats(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b).unwrap_or_else(|| a.total_cmp(&b))
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Str(a), Value::Str(b)) => a.cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Float(_), _) | (_, Value::Float(_)) if self.rank() == other.rank() => {
+                compare_floats(self.to_float().unwrap(), other.to_float().unwrap())
+            }
+            (Value::BigInt(_), _) | (_, Value::BigInt(_)) if self.rank() == other.rank() => {
+                self.to_big().unwrap().cmp(&other.to_big().unwrap())
+            }
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::List(a), Value::List(b)) => a.cmp(b),
+            (Value::Map(a), Value::Map(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
 #[derive(Debug, Clone, Default)]
 pub struct FunctionContext {
-    parameters: Vec<String>,
-    statements: Vec<Statement>,
-    static_variables: Scope,
+    pub(crate) parameters: Vec<String>,
+    pub(crate) statements: Vec<Statement>,
+    pub(crate) static_variables: Scope,
+    /// Text of the definition and the line it starts on, used to save the function
+    pub(crate) source: String,
+    pub(crate) line: usize,
 }
 
-#[derive(Clone)]
 pub struct Context {
-    global_variables: Scope,
-    function_contexts: HashMap<String, FunctionContext>,
-    builtins: HashMap<String, Builtin>,
+    pub(crate) global_variables: Scope,
+    pub(crate) function_contexts: HashMap<String, FunctionContext>,
+    builtins: HashMap<String, Box<dyn Builtin>>,
+    /// Whether int arithmetic overflows into big integers rather than failing
+    pub big_integers: bool,
+    /// Source of randomness for builtins, which can be seeded to make runs reproducible
+    pub(crate) rng: ChaCha8Rng,
+    pub io: Box<dyn Io>,
+    /// The state of the story as the running step started, which is what `save` records
+    pub(crate) checkpoint: Option<Checkpoint>,
+    /// Where in the section a `load` carries on from, picked up by the interpreter along with the
+    /// goto the load returns
+    pub(crate) resume: Option<Position>,
+    /// The section and position to return to when each section which was called ends, kept here
+    /// so that saving and loading carry it along
+    pub(crate) call_stack: Vec<(usize, Position)>,
 }
 
 impl Context {
-    pub fn new(builtins: HashMap<String, Builtin>) -> Self {
+    pub fn new(builtins: HashMap<String, Box<dyn Builtin>>) -> Self {
         Self {
             global_variables: Default::default(),
             function_contexts: Default::default(),
             builtins,
+            big_integers: false,
+            rng: ChaCha8Rng::from_entropy(),
+            io: Box::new(Terminal),
+            checkpoint: None,
+            resume: None,
+            call_stack: vec![],
         }
     }
+
+    /// Make random choices reproducible, the same seed always gives the same choices
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    /// Make a builtin available to scripts, replacing any existing builtin with the same name
+    pub fn register_builtin(&mut self, name: &str, builtin: impl Builtin + 'static) {
+        self.builtins.insert(name.to_owned(), Box::new(builtin));
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StatementResult {
     Continue,
     Goto(String),
+    Call(String),
+    ReturnSection,
     Return(Value),
+    Break,
+    ContinueLoop,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -163,7 +553,11 @@ pub enum ExpressionResult {
 impl Context {
     pub fn eval(&mut self, statements: &[Statement]) -> Result<StatementResult> {
         let mut local_variables: Scope = Default::default();
-        self.eval_statements(statements, &mut local_variables, &None)
+        match self.eval_statements(statements, &mut local_variables, &None)? {
+            StatementResult::Break => bail!(RundownError::BreakOutsideLoop),
+            StatementResult::ContinueLoop => bail!(RundownError::ContinueOutsideLoop),
+            result => Ok(result),
+        }
     }
 
     fn eval_statements(
@@ -184,25 +578,69 @@ impl Context {
         Ok(StatementResult::Continue)
     }
 
+    /// Evaluate a single iteration of a loop body. Local variables declared within the body only
+    /// live until the end of the iteration, so that they can be declared again by the next one.
+    fn eval_loop_body(
+        &mut self,
+        statements: &[Statement],
+        local_variables: &mut Scope,
+        function: &Option<String>,
+    ) -> Result<StatementResult> {
+        let outer_variables: HashSet<String> = local_variables.keys().cloned().collect();
+        let result = self.eval_statements(statements, local_variables, function);
+        local_variables.retain(|name, _| outer_variables.contains(name));
+        result
+    }
+
     fn eval_statement(
         &mut self,
         statement: &Statement,
         local_variables: &mut Scope,
         function: &Option<String>,
     ) -> Result<StatementResult> {
-        match statement {
-            Statement::Goto(expression) => {
-                match self.eval_expression(&*expression, local_variables, function)? {
+        self.eval_statement_kind(statement, local_variables, function)
+            .map_err(|e| with_span(e, statement.span))
+    }
+
+    fn eval_statement_kind(
+        &mut self,
+        statement: &Statement,
+        local_variables: &mut Scope,
+        function: &Option<String>,
+    ) -> Result<StatementResult> {
+        match &statement.kind {
+            StatementKind::Call(expression) => {
+                if let Some(function) = function {
+                    bail!(RundownError::SectionCallInFunction(function.clone()));
+                }
+                match self.eval_expression(expression, local_variables, function)? {
+                    ExpressionResult::Value(Value::Str(s)) => return Ok(StatementResult::Call(s)),
+                    ExpressionResult::Goto(s) => {
+                        return Ok(StatementResult::Goto(s));
+                    }
+                    ExpressionResult::Value(v) => {
+                        bail!(RundownError::InvalidLabel(v.type_name()));
+                    }
+                }
+            }
+            StatementKind::ReturnSection => {
+                if let Some(function) = function {
+                    bail!(RundownError::SectionCallInFunction(function.clone()));
+                }
+                return Ok(StatementResult::ReturnSection);
+            }
+            StatementKind::Goto(expression) => {
+                match self.eval_expression(expression, local_variables, function)? {
                     ExpressionResult::Value(Value::Str(s)) => return Ok(StatementResult::Goto(s)),
                     ExpressionResult::Goto(s) => {
                         return Ok(StatementResult::Goto(s));
                     }
-                    _ => {
-                        bail!("Attempted to jump to a non-string label");
+                    ExpressionResult::Value(v) => {
+                        bail!(RundownError::InvalidLabel(v.type_name()));
                     }
                 }
             }
-            Statement::Declare {
+            StatementKind::Declare {
                 scope: ScopeSpecifier::Global,
                 name,
                 expression,
@@ -220,19 +658,19 @@ impl Context {
                     }
                 }
             }
-            Statement::Declare {
+            StatementKind::Declare {
                 scope: ScopeSpecifier::Static,
                 name,
                 expression,
             } => {
                 if function.is_none() {
-                    bail!("Attempted to define a static variable outside of a function context");
+                    bail!(RundownError::StaticOutsideFunction(name.clone()));
                 }
 
                 if function
                     .as_ref()
                     .and_then(|f| self.function_contexts.get(f))
-                    .map_or(false, |ctx| ctx.static_variables.contains_key(name))
+                    .is_some_and(|ctx| ctx.static_variables.contains_key(name))
                 {
                     return Ok(StatementResult::Continue);
                 }
@@ -252,13 +690,13 @@ impl Context {
                     }
                 }
             }
-            Statement::Declare {
+            StatementKind::Declare {
                 scope: ScopeSpecifier::Local,
                 name,
                 expression,
             } => {
                 if local_variables.contains_key(name) {
-                    bail!("Attemped to redefine local variable");
+                    bail!(RundownError::RedefinedVariable(name.clone()));
                 }
 
                 match self.eval_expression(expression, local_variables, function)? {
@@ -270,7 +708,12 @@ impl Context {
                     }
                 }
             }
-            Statement::Assignment { name, expression } => {
+            StatementKind::Assignment {
+                name,
+                indices,
+                operator,
+                expression,
+            } => {
                 // TODO: If this expression contains a function call which has side effects,
                 // but the assignment fails, this will still perform the side-effects.
                 let value = match self.eval_expression(expression, local_variables, function)? {
@@ -279,21 +722,41 @@ impl Context {
                         return Ok(StatementResult::Goto(s));
                     }
                 };
-                if let Some(v) = local_variables.get_mut(name) {
-                    *v = value;
-                } else if let Some(v) = function.as_ref().and_then(|f| {
-                    self.function_contexts
-                        .get_mut(f)
-                        .and_then(|ctx| ctx.static_variables.get_mut(name))
-                }) {
-                    *v = value;
-                } else if let Some(v) = self.global_variables.get_mut(name) {
-                    *v = value;
-                } else {
-                    bail!("Attempted to assign to an undeclared variable");
+                let mut index_values = vec![];
+                for index in indices {
+                    match self.eval_expression(index, local_variables, function)? {
+                        ExpressionResult::Value(v) => index_values.push(v),
+                        ExpressionResult::Goto(s) => {
+                            return Ok(StatementResult::Goto(s));
+                        }
+                    }
+                }
+
+                let big_integers = self.big_integers;
+                let variable = self
+                    .variable_mut(name, local_variables, function)
+                    .ok_or_else(|| RundownError::UndeclaredVariable(name.clone()))?;
+                match (operator, index_values.split_last()) {
+                    (None, None) => *variable = value,
+                    (None, Some((last, path))) => {
+                        let mut target = variable;
+                        for index in path {
+                            target = target.index_mut(index)?;
+                        }
+                        target.set_index(last.clone(), value)?;
+                    }
+                    // Compound assignments update an existing element, so every index must exist
+                    (Some(operator), _) => {
+                        let mut target = variable;
+                        for index in &index_values {
+                            target = target.index_mut(index)?;
+                        }
+                        *target =
+                            apply_binary_operator(operator, target.clone(), value, big_integers)?;
+                    }
                 }
             }
-            Statement::If {
+            StatementKind::If {
                 conditional,
                 statements,
                 else_statements,
@@ -312,13 +775,118 @@ impl Context {
                     return self.eval_statements(else_statements, local_variables, function);
                 }
             }
-            Statement::FunctionDefinition {
+            StatementKind::While {
+                conditional,
+                statements,
+            } => loop {
+                let conditional =
+                    match self.eval_expression(conditional, local_variables, function)? {
+                        ExpressionResult::Value(v) => v.as_bool(),
+                        ExpressionResult::Goto(s) => {
+                            return Ok(StatementResult::Goto(s));
+                        }
+                    };
+
+                if !conditional {
+                    break;
+                }
+
+                match self.eval_loop_body(statements, local_variables, function)? {
+                    StatementResult::Continue | StatementResult::ContinueLoop => (),
+                    StatementResult::Break => break,
+                    exit => {
+                        return Ok(exit);
+                    }
+                }
+            },
+            StatementKind::For {
+                variable,
+                iterable,
+                statements,
+            } => {
+                let values: Box<dyn Iterator<Item = Value>> = match iterable {
+                    Iterable::Range { start, end } => {
+                        let start = match self.eval_expression(start, local_variables, function)? {
+                            ExpressionResult::Value(v) => v,
+                            ExpressionResult::Goto(s) => {
+                                return Ok(StatementResult::Goto(s));
+                            }
+                        };
+                        let end = match self.eval_expression(end, local_variables, function)? {
+                            ExpressionResult::Value(v) => v,
+                            ExpressionResult::Goto(s) => {
+                                return Ok(StatementResult::Goto(s));
+                            }
+                        };
+
+                        match (start, end) {
+                            (Value::Int(start), Value::Int(end)) => {
+                                Box::new((start..end).map(Value::Int))
+                            }
+                            (start, end) => bail!(RundownError::TypeMismatch {
+                                operator: "..".to_owned(),
+                                left: start.type_name(),
+                                right: end.type_name(),
+                            }),
+                        }
+                    }
+                    Iterable::Expression(expression) => {
+                        match self.eval_expression(expression, local_variables, function)? {
+                            ExpressionResult::Value(v) => Box::new(v.elements()?.into_iter()),
+                            ExpressionResult::Goto(s) => {
+                                return Ok(StatementResult::Goto(s));
+                            }
+                        }
+                    }
+                };
+
+                // The loop variable shadows any local of the same name for the duration of the loop
+                let shadowed = local_variables.remove(variable);
+                let mut result = StatementResult::Continue;
+                for value in values {
+                    local_variables.insert(variable.clone(), value);
+                    match self.eval_loop_body(statements, local_variables, function)? {
+                        StatementResult::Continue | StatementResult::ContinueLoop => (),
+                        StatementResult::Break => break,
+                        exit => {
+                            result = exit;
+                            break;
+                        }
+                    }
+                }
+
+                local_variables.remove(variable);
+                if let Some(shadowed) = shadowed {
+                    local_variables.insert(variable.clone(), shadowed);
+                }
+
+                return Ok(result);
+            }
+            StatementKind::Match { expression, arms } => {
+                let value = match self.eval_expression(expression, local_variables, function)? {
+                    ExpressionResult::Value(v) => v,
+                    ExpressionResult::Goto(s) => {
+                        return Ok(StatementResult::Goto(s));
+                    }
+                };
+
+                if let Some(arm) = arms
+                    .iter()
+                    .find(|arm| arm.patterns.iter().any(|pattern| value.matches(pattern)))
+                {
+                    return self.eval_statements(&arm.statements, local_variables, function);
+                }
+            }
+            StatementKind::Break => return Ok(StatementResult::Break),
+            StatementKind::Continue => return Ok(StatementResult::ContinueLoop),
+            StatementKind::FunctionDefinition {
                 name,
                 parameters,
                 statements,
+                source,
             } => {
                 if function.is_some() {
-                    bail!("Attempted to define a function within a function context");
+                    bail!(RundownError::NestedFunction(name.clone()));
                 }
 
                 self.function_contexts.insert(
@@ -326,18 +894,20 @@ impl Context {
                     FunctionContext {
                         parameters: parameters.clone(),
                         statements: statements.clone(),
+                        source: source.clone(),
+                        line: statement.span.line,
                         ..Default::default()
                     },
                 );
             }
-            Statement::Expression(expression) => {
+            StatementKind::Expression(expression) => {
                 if let ExpressionResult::Goto(s) =
                     self.eval_expression(expression, local_variables, function)?
                 {
                     return Ok(StatementResult::Goto(s));
                 }
             }
-            Statement::Return(expression) => {
+            StatementKind::Return(expression) => {
                 match self.eval_expression(expression, local_variables, function)? {
                     ExpressionResult::Value(v) => return Ok(StatementResult::Return(v)),
                     ExpressionResult::Goto(s) => {
@@ -350,17 +920,86 @@ impl Context {
         Ok(StatementResult::Continue)
     }
 
+    /// Evaluate an expression outside of any code block, so only global variables are in scope
+    pub(crate) fn eval_value(&mut self, expression: &Expression) -> Result<Value> {
+        match self.eval_expression(expression, &mut Default::default(), &None)? {
+            ExpressionResult::Value(v) => Ok(v),
+            ExpressionResult::Goto(label) => bail!(RundownError::GotoInTemplate(label)),
+        }
+    }
+
     fn eval_expression(
         &mut self,
         expression: &Expression,
         local_variables: &mut Scope,
         function: &Option<String>,
+    ) -> Result<ExpressionResult> {
+        self.eval_expression_kind(&expression.kind, local_variables, function)
+            .map_err(|e| with_span(e, expression.span))
+    }
+
+    /// Find a variable to assign to, looking through the local, static and then global scopes
+    fn variable_mut<'a>(
+        &'a mut self,
+        name: &str,
+        local_variables: &'a mut Scope,
+        function: &Option<String>,
+    ) -> Option<&'a mut Value> {
+        if let Some(v) = local_variables.get_mut(name) {
+            return Some(v);
+        }
+
+        let Context {
+            function_contexts,
+            global_variables,
+            ..
+        } = self;
+        if let Some(ctx) = function
+            .as_ref()
+            .and_then(move |f| function_contexts.get_mut(f))
+        {
+            if let Some(v) = ctx.static_variables.get_mut(name) {
+                return Some(v);
+            }
+        }
+
+        global_variables.get_mut(name)
+    }
+
+    fn eval_expression_kind(
+        &mut self,
+        expression: &ExpressionKind,
+        local_variables: &mut Scope,
+        function: &Option<String>,
     ) -> Result<ExpressionResult> {
         match expression {
-            Expression::Str(s) => Ok(ExpressionResult::Value(Value::Str(s.clone()))),
-            Expression::Int(i) => Ok(ExpressionResult::Value(Value::Int(*i))),
-            Expression::Bool(b) => Ok(ExpressionResult::Value(Value::Bool(*b))),
-            Expression::Ident(name) => {
+            ExpressionKind::Str(s) => Ok(ExpressionResult::Value(Value::Str(s.clone()))),
+            ExpressionKind::Interpolation(parts) => {
+                let mut s = String::new();
+                for part in parts {
+                    match part {
+                        StringPart::Literal(literal) => s.push_str(literal),
+                        StringPart::Expression(expression) => {
+                            match self.eval_expression(expression, local_variables, function)? {
+                                ExpressionResult::Value(v) => s.push_str(&v.to_string()),
+                                goto => {
+                                    return Ok(goto);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                Ok(ExpressionResult::Value(Value::Str(s)))
+            }
+            ExpressionKind::Int(i) => Ok(ExpressionResult::Value(Value::Int(*i))),
+            ExpressionKind::BigInt(n) if self.big_integers => {
+                Ok(ExpressionResult::Value(Value::BigInt(n.clone())))
+            }
+            ExpressionKind::BigInt(n) => Err(RundownError::Overflow(n.to_string()).into()),
+            ExpressionKind::Float(f) => Ok(ExpressionResult::Value(Value::Float(*f))),
+            ExpressionKind::Bool(b) => Ok(ExpressionResult::Value(Value::Bool(*b))),
+            ExpressionKind::Ident(name) => {
                 if let Some(v) = local_variables.get(name) {
                     Ok(ExpressionResult::Value(v.clone()))
                 } else if let Some(v) = function.as_ref().and_then(|f| {
@@ -372,10 +1011,10 @@ impl Context {
                 } else if let Some(v) = self.global_variables.get(name) {
                     Ok(ExpressionResult::Value(v.clone()))
                 } else {
-                    Err(anyhow!("Attempted to access an undeclared variable"))
+                    Err(RundownError::UndeclaredVariable(name.clone()).into())
                 }
             }
-            Expression::BinaryExpression {
+            ExpressionKind::BinaryExpression {
                 operator,
                 left,
                 right,
@@ -386,43 +1025,88 @@ impl Context {
                         return Ok(goto);
                     }
                 };
+
+                // Short circuit, so that the right hand side and any side effects it has, such as
+                // reading input or a goto from a function call, only happen if it affects the result
+                match operator {
+                    BinaryOperator::And if !lhs.as_bool() => {
+                        return Ok(ExpressionResult::Value(Value::Bool(false)));
+                    }
+                    BinaryOperator::Or if lhs.as_bool() => {
+                        return Ok(ExpressionResult::Value(Value::Bool(true)));
+                    }
+                    _ => (),
+                }
+
                 let rhs = match self.eval_expression(right, local_variables, function)? {
                     ExpressionResult::Value(v) => v,
                     goto => {
                         return Ok(goto);
                     }
                 };
-
-                match operator {
-                    BinaryOperator::Add => Ok(ExpressionResult::Value(lhs.add(rhs)?)),
-                    BinaryOperator::Subtract => Ok(ExpressionResult::Value(lhs.subtract(rhs)?)),
-                    BinaryOperator::Multiply => Ok(ExpressionResult::Value(lhs.multiply(rhs)?)),
-                    BinaryOperator::Divide => Ok(ExpressionResult::Value(lhs.divide(rhs)?)),
-                    BinaryOperator::Modulo => Ok(ExpressionResult::Value(lhs.modulo(rhs)?)),
-                    BinaryOperator::And => Ok(ExpressionResult::Value(lhs.and(rhs)?)),
-                    BinaryOperator::Or => Ok(ExpressionResult::Value(lhs.or(rhs)?)),
-                    BinaryOperator::Equals => Ok(ExpressionResult::Value(Value::Bool(lhs == rhs))),
-                    BinaryOperator::NotEquals => {
-                        Ok(ExpressionResult::Value(Value::Bool(lhs != rhs)))
-                    }
-                    BinaryOperator::GreaterThanEquals => {
-                        Ok(ExpressionResult::Value(Value::Bool(lhs >= rhs)))
+                Ok(ExpressionResult::Value(apply_binary_operator(
+                    operator,
+                    lhs,
+                    rhs,
+                    self.big_integers,
+                )?))
+            }
+            ExpressionKind::Index { target, index } => {
+                let target = match self.eval_expression(target, local_variables, function)? {
+                    ExpressionResult::Value(v) => v,
+                    goto => {
+                        return Ok(goto);
                     }
-                    BinaryOperator::LessThanEquals => {
-                        Ok(ExpressionResult::Value(Value::Bool(lhs <= rhs)))
+                };
+                let index = match self.eval_expression(index, local_variables, function)? {
+                    ExpressionResult::Value(v) => v,
+                    goto => {
+                        return Ok(goto);
                     }
-                    BinaryOperator::GreaterThan => {
-                        Ok(ExpressionResult::Value(Value::Bool(lhs > rhs)))
+                };
+
+                Ok(ExpressionResult::Value(target.index(&index)?))
+            }
+            ExpressionKind::List(elements) => {
+                let mut values = vec![];
+                for element in elements {
+                    match self.eval_expression(element, local_variables, function)? {
+                        ExpressionResult::Value(v) => values.push(v),
+                        goto => {
+                            return Ok(goto);
+                        }
                     }
-                    BinaryOperator::LessThan => Ok(ExpressionResult::Value(Value::Bool(lhs < rhs))),
                 }
+
+                Ok(ExpressionResult::Value(Value::List(values)))
+            }
+            ExpressionKind::Map(entries) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    let key = match self.eval_expression(key, local_variables, function)? {
+                        ExpressionResult::Value(v) => v,
+                        goto => {
+                            return Ok(goto);
+                        }
+                    };
+                    let value = match self.eval_expression(value, local_variables, function)? {
+                        ExpressionResult::Value(v) => v,
+                        goto => {
+                            return Ok(goto);
+                        }
+                    };
+                    map.insert(key, value);
+                }
+
+                Ok(ExpressionResult::Value(Value::Map(map)))
             }
-            Expression::UnaryExpression {
+            ExpressionKind::UnaryExpression {
                 operator,
                 expression,
             } => {
                 let expression =
                     match self.eval_expression(expression, local_variables, function)? {
+                        ExpressionResult::Value(v) if self.big_integers => v.widen(),
                         ExpressionResult::Value(v) => v,
                         goto => {
                             return Ok(goto);
@@ -434,10 +1118,10 @@ impl Context {
                     UnaryOperator::Negate => Ok(ExpressionResult::Value(expression.negate()?)),
                 }
             }
-            Expression::FunctionCall { name, arguments } => {
+            ExpressionKind::FunctionCall { name, arguments } => {
                 let arguments = arguments
                     .iter()
-                    .map(|arg| self.eval_expression(&arg, local_variables, function))
+                    .map(|arg| self.eval_expression(arg, local_variables, function))
                     .collect::<Result<Vec<_>>>()?;
 
                 // Check for early goto
@@ -455,33 +1139,618 @@ impl Context {
                     })
                     .collect();
 
-                if let Some(builtin) = self.builtins.get(name) {
-                    Ok(ExpressionResult::Value(builtin(&arguments)?))
+                // The builtin is taken out of the context while it runs, so that it can be given
+                // mutable access to the rest of the context
+                if let Some(mut builtin) = self.builtins.remove(name) {
+                    let result = builtin.call(self, &arguments);
+                    self.builtins.entry(name.clone()).or_insert(builtin);
+                    result.map_err(|e| {
+                        if e.is::<RundownError>() {
+                            e
+                        } else {
+                            RundownError::Builtin {
+                                name: name.clone(),
+                                message: e.to_string(),
+                            }
+                            .into()
+                        }
+                    })
                 } else {
                     let (statements, parameters) = self
                         .function_contexts
                         .get(name)
                         .map(|ctx| (ctx.statements.clone(), ctx.parameters.clone()))
-                        .ok_or_else(|| anyhow!("Attempted to call undefined function"))?;
+                        .ok_or_else(|| RundownError::UndefinedFunction(name.clone()))?;
 
                     if arguments.len() != parameters.len() {
-                        bail!("Incorrect number of parameters to function");
+                        bail!(RundownError::ArityMismatch {
+                            name: name.clone(),
+                            expected: parameters.len(),
+                            actual: arguments.len(),
+                        });
                     }
 
-                    let mut new_scope = parameters
-                        .into_iter()
-                        .zip(arguments.into_iter())
-                        .collect::<Scope>();
+                    let mut new_scope = parameters.into_iter().zip(arguments).collect::<Scope>();
 
                     match self.eval_statements(&statements, &mut new_scope, &Some(name.clone()))? {
                         StatementResult::Goto(s) => Ok(ExpressionResult::Goto(s)),
                         StatementResult::Return(v) => Ok(ExpressionResult::Value(v)),
+                        StatementResult::Break => bail!(RundownError::BreakOutsideLoop),
+                        StatementResult::ContinueLoop => bail!(RundownError::ContinueOutsideLoop),
+                        // Checked when the statements run, since they leave the section
+                        StatementResult::Call(_) | StatementResult::ReturnSection => {
+                            unreachable!("Section calls are not allowed in functions")
+                        }
                         // TOOD: We don't have a void type, so if we don't return from a function make
                         // this equivalent to return false
-                        _ => Ok(ExpressionResult::Value(Value::Bool(false))),
+                        StatementResult::Continue => {
+                            Ok(ExpressionResult::Value(Value::Bool(false)))
+                        }
                     }
                 }
             }
         }
     }
 }
+
+fn apply_binary_operator(
+    operator: &BinaryOperator,
+    lhs: Value,
+    rhs: Value,
+    big_integers: bool,
+) -> Result<Value> {
+    let (lhs, rhs) = match (lhs, rhs) {
+        (lhs @ Value::Int(_), rhs @ Value::Int(_)) if big_integers => (lhs.widen(), rhs.widen()),
+        operands => operands,
+    };
+
+    match operator {
+        BinaryOperator::Add => lhs.add(rhs),
+        BinaryOperator::Subtract => lhs.subtract(rhs),
+        BinaryOperator::Multiply => lhs.multiply(rhs),
+        BinaryOperator::Divide => lhs.divide(rhs),
+        BinaryOperator::Modulo => lhs.modulo(rhs),
+        BinaryOperator::And => lhs.and(rhs),
+        BinaryOperator::Or => lhs.or(rhs),
+        BinaryOperator::Equals => Ok(Value::Bool(lhs == rhs)),
+        BinaryOperator::NotEquals => Ok(Value::Bool(lhs != rhs)),
+        BinaryOperator::GreaterThanEquals => Ok(Value::Bool(lhs >= rhs)),
+        BinaryOperator::LessThanEquals => Ok(Value::Bool(lhs <= rhs)),
+        BinaryOperator::GreaterThan => Ok(Value::Bool(lhs > rhs)),
+        BinaryOperator::LessThan => Ok(Value::Bool(lhs < rhs)),
+        BinaryOperator::In => Ok(Value::Bool(rhs.contains(&lhs)?)),
+    }
+}
+
+/// Attach the location of the innermost statement or expression which caused an error, which can
+/// be recovered with `error.downcast_ref::<Span>()`
+fn with_span(error: Error, span: Span) -> Error {
+    if error.downcast_ref::<Span>().is_some() {
+        error
+    } else {
+        error.context(span)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast;
+    use crate::io::Scripted;
+
+    fn eval(source: &str) -> Result<StatementResult> {
+        let statements = ast::parse(source, 1)?;
+        Context::new(HashMap::new()).eval(&statements)
+    }
+
+    #[test]
+    fn and_short_circuits() {
+        let result = eval("let x = 0; if (x != 0 && 10 / x > 1) { goto \"a\"; }").unwrap();
+        assert_eq!(result, StatementResult::Continue);
+    }
+
+    #[test]
+    fn or_short_circuits() {
+        let result = eval("fun jump() { goto \"a\"; } return true || jump();").unwrap();
+        assert_eq!(result, StatementResult::Return(Value::Bool(true)));
+    }
+
+    #[test]
+    fn while_loop_with_break_and_continue() {
+        let source = "
+            let i = 0;
+            let total = 0;
+            while (true) {
+                i = i + 1;
+                if (i > 10) { break; }
+                if (i % 2 == 0) { continue; }
+                let odd = i;
+                total = total + odd;
+            }
+            return total;
+        ";
+        assert_eq!(
+            eval(source).unwrap(),
+            StatementResult::Return(Value::Int(25))
+        );
+    }
+
+    #[test]
+    fn for_loop_over_range_and_string() {
+        let source = "
+            let total = 0;
+            for (i in 1..5) { total = total + i; }
+            let reversed = \"\";
+            for (c in \"abc\") { reversed = c + reversed; }
+            return reversed + total;
+        ";
+        assert_eq!(
+            eval(source).unwrap(),
+            StatementResult::Return(Value::Str("cba10".to_owned()))
+        );
+    }
+
+    #[test]
+    fn goto_escapes_loops() {
+        let source = "for (i in 0..100) { while (true) { goto \"a\"; } }";
+        assert_eq!(eval(source).unwrap(), StatementResult::Goto("a".to_owned()));
+        assert!(eval("break;").is_err());
+    }
+
+    #[test]
+    fn match_takes_first_matching_arm() {
+        let source = "
+            fun dispatch(response) {
+                match response {
+                    \"yes\" | \"y\" => return \"a\",
+                    1 | -1 => { return \"b\"; }
+                    true => return \"c\",
+                    \"yes\" => return \"unreachable\",
+                    _ => goto \"question\",
+                }
+            }
+            return dispatch(\"y\") + dispatch(-1) + dispatch(true) + dispatch(\"yes\");
+        ";
+        assert_eq!(
+            eval(source).unwrap(),
+            StatementResult::Return(Value::Str("abca".to_owned()))
+        );
+        assert_eq!(
+            eval("match 2 { 1 => goto \"one\", _ => goto \"other\" }").unwrap(),
+            StatementResult::Goto("other".to_owned())
+        );
+        assert_eq!(
+            eval("match 2 { 1 => goto \"one\" }").unwrap(),
+            StatementResult::Continue
+        );
+        assert_eq!(
+            eval("match 2.0 / 2 { 1 => goto \"one\", _ => goto \"other\" }").unwrap(),
+            StatementResult::Goto("one".to_owned())
+        );
+        assert_eq!(
+            eval("match 1 { true => goto \"true\", 1 => goto \"one\" }").unwrap(),
+            StatementResult::Goto("one".to_owned())
+        );
+    }
+
+    #[test]
+    fn right_hand_side_is_evaluated_when_needed() {
+        let result = eval("fun jump() { goto \"a\"; } return false || jump();").unwrap();
+        assert_eq!(result, StatementResult::Goto("a".to_owned()));
+        assert!(eval("let x = 0; return true && 10 / x;").is_err());
+    }
+
+    #[test]
+    fn lists_and_maps() {
+        let source = "
+            let inventory = [\"sword\"];
+            let stats = {\"gold\": 10};
+            inventory = inventory + [\"key\"];
+            inventory[0] = \"axe\";
+            stats[\"gold\"] = stats[\"gold\"] + 5;
+            stats[\"hp\"] = 3;
+            let found = \"\";
+            for (key in stats) {
+                found = found + key;
+            }
+            return [inventory, stats[\"gold\"], \"key\" in inventory, \"mp\" in stats, found];
+        ";
+        let result = eval(source).unwrap();
+        let expected = Value::List(vec![
+            Value::List(vec![
+                Value::Str("axe".to_owned()),
+                Value::Str("key".to_owned()),
+            ]),
+            Value::Int(15),
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Str("goldhp".to_owned()),
+        ]);
+        assert_eq!(result, StatementResult::Return(expected));
+        assert_eq!(
+            Value::List(vec![Value::Str("a".to_owned()), Value::Int(1)]).to_string(),
+            "[\"a\", 1]"
+        );
+
+        let error = eval("let l = [1]; return l[1];").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RundownError>(),
+            Some(RundownError::IndexOutOfBounds {
+                index: 1,
+                length: 1
+            })
+        ));
+        assert!(eval("let m = {}; return m[\"missing\"];").is_err());
+    }
+
+    #[test]
+    fn ints_are_promoted_to_floats() {
+        let result = eval("return [7 / 2, 7 / 2.0, 1 + 0.5, 2.5 * 2, 1 == 1.0, 1 < 1.5];").unwrap();
+        let expected = Value::List(vec![
+            Value::Int(3),
+            Value::Float(3.5),
+            Value::Float(1.5),
+            Value::Float(5.0),
+            Value::Bool(true),
+            Value::Bool(true),
+        ]);
+        assert_eq!(result, StatementResult::Return(expected));
+        assert_eq!(Value::Float(5.0).to_string(), "5.0");
+        assert!(eval("return 1.0 / 0;").is_err());
+    }
+
+    #[test]
+    fn read_only_converts_plain_numbers() {
+        let answers = ["12", "-2.5", "3.", "nan", "inf", "Infinity", "1e3", "TRUE"];
+        let mut context = Context::new(crate::builtins::builtins());
+        context.io = Box::new(Scripted::new(answers.iter().map(|s| s.to_string())));
+        let statements = ast::parse(&format!("return [{}];", ["read()"; 8].join(", ")), 1).unwrap();
+        let strings = ["3.", "nan", "inf", "infinity", "1e3"];
+        let mut expected = vec![Value::Int(12), Value::Float(-2.5)];
+        expected.extend(strings.iter().map(|s| Value::Str(s.to_string())));
+        expected.push(Value::Bool(true));
+        assert_eq!(
+            context.eval(&statements).unwrap(),
+            StatementResult::Return(Value::List(expected))
+        );
+    }
+
+    fn eval_with_builtins(source: &str) -> Result<StatementResult> {
+        let statements = ast::parse(source, 1)?;
+        Context::new(crate::builtins::builtins()).eval(&statements)
+    }
+
+    #[test]
+    fn string_builtins() {
+        let source = r#"return [
+            upper("abc"), lower("ÀBC"), trim("  a b  "),
+            contains("hello", "ell"), starts_with("hello", "lo"), ends_with("hello", "lo"),
+            split("a,b,,c", ","), split("hé", ""), join([1, "a", true], "-"),
+            replace("aaa", "a", "bc"), substr("héllo", 1, 3), substr("abc", 2, 10),
+            repeat("ab", 3), index_of("héllo", "l"), index_of("abc", "z"),
+            format("{} has {{{}}}", "Ann", 3)
+        ];"#;
+        let s = |s: &str| Value::Str(s.to_owned());
+        let expected = vec![
+            s("ABC"),
+            s("àbc"),
+            s("a b"),
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::List(vec![s("a"), s("b"), s(""), s("c")]),
+            Value::List(vec![s("h"), s("é")]),
+            s("1-a-true"),
+            s("bcbcbc"),
+            s("éll"),
+            s("c"),
+            s("ababab"),
+            Value::Int(2),
+            Value::Int(-1),
+            s("Ann has {3}"),
+        ];
+        assert_eq!(
+            eval_with_builtins(source).unwrap(),
+            StatementResult::Return(Value::List(expected))
+        );
+    }
+
+    #[test]
+    fn string_builtins_check_their_arguments() {
+        let error = |source: &str| eval_with_builtins(source).unwrap_err();
+        assert!(matches!(
+            error("upper(1);").downcast_ref::<RundownError>(),
+            Some(RundownError::InvalidArgument { name, .. }) if name == "upper"
+        ));
+        assert!(matches!(
+            error("join(\"ab\", \",\");").downcast_ref::<RundownError>(),
+            Some(RundownError::InvalidArgument { name, .. }) if name == "join"
+        ));
+        assert!(matches!(
+            error("trim();").downcast_ref::<RundownError>(),
+            Some(RundownError::ArityMismatch { name, .. }) if name == "trim"
+        ));
+        assert!(matches!(
+            error("substr(\"abc\", 4, 1);").downcast_ref::<RundownError>(),
+            Some(RundownError::IndexOutOfBounds {
+                index: 4,
+                length: 3
+            })
+        ));
+        assert!(matches!(
+            error("repeat(\"a\", -1);").downcast_ref::<RundownError>(),
+            Some(RundownError::NegativeRepeatCount(-1))
+        ));
+        assert!(eval_with_builtins("format(\"{} {}\", 1);").is_err());
+        assert!(eval_with_builtins("format(\"{}\", 1, 2);").is_err());
+    }
+
+    #[test]
+    fn read_line_keeps_answers_as_typed() {
+        let mut context = Context::new(crate::builtins::builtins());
+        context.io = Box::new(Scripted::new(vec![
+            "Ada Lovelace ".to_owned(),
+            "12".to_owned(),
+        ]));
+        let statements = ast::parse("return [read_line(), read_line()];", 1).unwrap();
+        assert_eq!(
+            context.eval(&statements).unwrap(),
+            StatementResult::Return(Value::List(vec![
+                Value::Str("Ada Lovelace ".to_owned()),
+                Value::Str("12".to_owned()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let error = eval("return 9223372036854775807 + 1;").unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            RundownError::Overflow("9223372036854775807 + 1".to_owned()).to_string()
+        );
+        assert!(eval("return -9223372036854775807 - 1 / -1;").is_ok());
+        assert!(eval("return (-9223372036854775807 - 1) / -1;").is_err());
+        assert!(eval("return (-9223372036854775807 - 1) % -1;").is_err());
+        assert!(matches!(
+            eval("return 7 % 0;").unwrap_err().downcast_ref::<RundownError>(),
+            Some(RundownError::DivisionByZero(expression)) if expression == "7 % 0"
+        ));
+    }
+
+    #[test]
+    fn big_integers_do_not_overflow() {
+        let statements = ast::parse(
+            "let x = 1; for (i in 0..100) { x = x * 2; } return [x, x / x, x > 1.0];",
+            1,
+        )
+        .unwrap();
+        let mut context = Context::new(HashMap::new());
+        context.big_integers = true;
+        match context.eval(&statements).unwrap() {
+            StatementResult::Return(value) => assert_eq!(
+                value.to_string(),
+                "[1267650600228229401496703205376, 1, true]"
+            ),
+            result => panic!("Expected a return, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn int_literals_too_large_for_an_int_need_big_integers() {
+        let statements = ast::parse("return 9223372036854775808 - 1;", 1).unwrap();
+        let mut context = Context::new(HashMap::new());
+        assert!(matches!(
+            context.eval(&statements).unwrap_err().downcast_ref::<RundownError>(),
+            Some(RundownError::Overflow(literal)) if literal == "9223372036854775808"
+        ));
+        context.big_integers = true;
+        assert_eq!(
+            context.eval(&statements).unwrap(),
+            StatementResult::Return(Value::Int(i64::MAX))
+        );
+        assert_eq!(
+            eval("return -9223372036854775808;").unwrap(),
+            StatementResult::Return(Value::Int(i64::MIN))
+        );
+    }
+
+    #[test]
+    fn compound_assignment() {
+        let source = "
+            let global total = 1;
+            let stats = {\"gold\": 10};
+            fun count() {
+                let static calls = 0;
+                calls += 1;
+                total *= 3;
+                return calls;
+            }
+            count();
+            stats[\"gold\"] -= count();
+            let i = 17;
+            i /= 2;
+            i %= 5;
+            return [total, stats, i];
+        ";
+        let result = eval(source).unwrap();
+        assert_eq!(
+            result,
+            StatementResult::Return(Value::List(vec![
+                Value::Int(9),
+                Value::Map(
+                    vec![(Value::Str("gold".to_owned()), Value::Int(8))]
+                        .into_iter()
+                        .collect()
+                ),
+                Value::Int(3),
+            ]))
+        );
+        assert!(eval("let m = {}; m[\"missing\"] += 1;").is_err());
+        assert!(eval("undeclared += 1;").is_err());
+    }
+
+    #[test]
+    fn seeded_random_choices_are_reproducible() {
+        let statements = ast::parse(
+            "return [random(1, 100), random(0.0, 1.0), choice([1, 2, 3]), shuffle([1, 2, 3, 4, 5])];",
+            1,
+        )
+        .unwrap();
+        let run = || {
+            let mut context = Context::new(crate::builtins::builtins());
+            context.seed_rng(42);
+            (0..3)
+                .map(|_| context.eval(&statements).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn number_builtins() {
+        let source = "return [min(3, 1.5, 2), max([4, 9, 2]), min(-1), pow(2, 10), pow(2, -1), pow(4, 0.5), abs(-3), clamp(15, 0, 10), clamp(0.5, 0, 1)];";
+        let expected = vec![
+            Value::Float(1.5),
+            Value::Int(9),
+            Value::Int(-1),
+            Value::Int(1024),
+            Value::Float(0.5),
+            Value::Float(2.0),
+            Value::Int(3),
+            Value::Int(10),
+            Value::Float(0.5),
+        ];
+        assert_eq!(
+            eval_with_builtins(source).unwrap(),
+            StatementResult::Return(Value::List(expected))
+        );
+
+        let statements = ast::parse("return pow(2, 64);", 1).unwrap();
+        let mut context = Context::new(crate::builtins::builtins());
+        assert!(matches!(
+            context
+                .eval(&statements)
+                .unwrap_err()
+                .downcast_ref::<RundownError>(),
+            Some(RundownError::Overflow(_))
+        ));
+        context.big_integers = true;
+        assert_eq!(
+            context.eval(&statements).unwrap(),
+            StatementResult::Return(Value::BigInt(BigInt::from(1u128 << 64)))
+        );
+
+        // Bases which never grow work with any exponent, and others are limited even with big
+        // integers
+        let source = "return [pow(0, 5000000000), pow(1, 5000000000), pow(-1, 5000000001), pow(-1, 5000000000), pow(0, 0)];";
+        assert_eq!(
+            eval_with_builtins(source).unwrap(),
+            StatementResult::Return(Value::List(vec![
+                Value::Int(0),
+                Value::Int(1),
+                Value::Int(-1),
+                Value::Int(1),
+                Value::Int(1),
+            ]))
+        );
+        let statements = ast::parse("return pow(2, 5000000000);", 1).unwrap();
+        assert!(matches!(
+            context
+                .eval(&statements)
+                .unwrap_err()
+                .downcast_ref::<RundownError>(),
+            Some(RundownError::Builtin { name, .. }) if name == "pow"
+        ));
+    }
+
+    #[test]
+    fn number_builtins_check_their_arguments() {
+        for (source, builtin) in &[
+            ("choice([]);", "choice"),
+            ("clamp(5, 10, 1);", "clamp"),
+            ("random(6, 1);", "random"),
+            ("random(1.0, 1.0);", "random"),
+            ("min();", "min"),
+        ] {
+            assert!(matches!(
+                eval_with_builtins(source).unwrap_err().downcast_ref::<RundownError>(),
+                Some(RundownError::Builtin { name, .. }) if name == builtin
+            ));
+        }
+        assert!(matches!(
+            eval_with_builtins("max(1, \"a\");").unwrap_err().downcast_ref::<RundownError>(),
+            Some(RundownError::InvalidArgument { name, .. }) if name == "max"
+        ));
+    }
+
+    #[test]
+    fn errors_are_reported_as_rundown_errors() {
+        let cases = [
+            ("return \"a\" - 1;", "TypeMismatch"),
+            ("return -\"a\";", "InvalidOperand"),
+            ("return x;", "UndeclaredVariable"),
+            ("let a = 1; let a = 2;", "RedefinedVariable"),
+            ("let static a = 1;", "StaticOutsideFunction"),
+            ("nope();", "UndefinedFunction"),
+            (
+                "fun f() { fun g() { return 1; } return 1; } f();",
+                "NestedFunction",
+            ),
+            ("fun f(a) { return a; } f();", "ArityMismatch"),
+            ("upper(1);", "InvalidArgument"),
+            ("goto 1;", "InvalidLabel"),
+            ("for (x in 1) { x; }", "NotIterable"),
+            ("break;", "BreakOutsideLoop"),
+            ("continue;", "ContinueOutsideLoop"),
+            ("return [1][2];", "IndexOutOfBounds"),
+            ("return {\"a\": 1}[\"b\"];", "KeyNotFound"),
+            ("return 1 / 0;", "DivisionByZero"),
+            ("return 9223372036854775807 + 1;", "Overflow"),
+            ("repeat(\"a\", -1);", "NegativeRepeatCount"),
+            (
+                "fun f() { call \"a\"; return 1; } f();",
+                "SectionCallInFunction",
+            ),
+            ("choice([]);", "Builtin"),
+        ];
+        for (source, variant) in &cases {
+            let error = eval_with_builtins(source).unwrap_err();
+            let debug = match error.downcast_ref::<RundownError>() {
+                Some(error) => format!("{:?}", error),
+                None => panic!("`{}` failed with {:?}", source, error),
+            };
+            assert!(
+                debug.starts_with(variant),
+                "`{}` failed with {}, not {}",
+                source,
+                debug,
+                variant
+            );
+        }
+    }
+
+    #[test]
+    fn builtins_can_hold_state_and_goto() {
+        let statements = ast::parse("count(); count(); return count();", 1).unwrap();
+        let mut context = Context::new(HashMap::new());
+        let mut calls = 0;
+        context.register_builtin("count", move |_: &mut Context, _: &[Value]| {
+            calls += 1;
+            Ok(ExpressionResult::Value(Value::Int(calls)))
+        });
+        assert_eq!(
+            context.eval(&statements).unwrap(),
+            StatementResult::Return(Value::Int(3))
+        );
+
+        let statements = ast::parse("if (jump()) { return 1; }", 1).unwrap();
+        context.register_builtin("jump", |_: &mut Context, _: &[Value]| {
+            Ok(ExpressionResult::Goto("elsewhere".to_owned()))
+        });
+        assert_eq!(
+            context.eval(&statements).unwrap(),
+            StatementResult::Goto("elsewhere".to_owned())
+        );
+    }
+}
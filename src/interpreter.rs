@@ -0,0 +1,645 @@
+use std::path::Path;
+
+use ::markdown::{generate_markdown, Block};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ast;
+use crate::builtins::builtins;
+use crate::diagnostic::{annotate, error_line, Location};
+use crate::document::Document;
+use crate::error::RundownError;
+use crate::eval::{Builtin, Context, ExpressionResult, StatementResult, Value};
+use crate::io::Io;
+use crate::markdown::{
+    condition_marker, is_menu_marker, ConditionMarker, SectionBlock, RUNDOWN_CODE_BLOCK_SYNTAX,
+};
+use crate::menu::Menu;
+use crate::save::{self, document_hash, load_builtin, save_builtin, Checkpoint};
+use crate::template::{evaluate_condition, render_block};
+
+/// How deeply sections can `call` each other, so that a section which calls itself forever is an
+/// error rather than running out of memory
+const MAX_CALL_DEPTH: usize = 64;
+
+/// How far through a section the interpreter is, so that it can carry on from the middle of a
+/// section after a `call` returns
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Position {
+    /// Index of the next block to run
+    block: usize,
+    /// Number of rundown code blocks before it, for reporting errors
+    code_blocks: usize,
+    /// For each `<!-- if -->` the blocks are in, its condition, whether the blocks around it are
+    /// shown and whether its own blocks are
+    conditions: Vec<(String, bool, bool)>,
+}
+
+/// Runs a document one section at a time, rendering its prose and running its code blocks
+pub struct Interpreter {
+    document: Document,
+    /// Saves are only loaded into the document they were made from
+    document_hash: String,
+    context: Context,
+    /// Index of the section which will run on the next step
+    pc: usize,
+    /// Where in the section the next step starts
+    position: Position,
+}
+
+impl Interpreter {
+    pub fn new(document: Document) -> Self {
+        let mut context = Context::new(builtins());
+
+        // Restarting jumps back to the first section, without resetting any variables
+        if let Some(first) = document.sections().next() {
+            let first = first.to_owned();
+            context.register_builtin("restart", move |_: &mut Context, _: &[Value]| {
+                Ok(ExpressionResult::Goto(first.clone()))
+            });
+        }
+
+        let hash = document_hash(&document.source);
+        context.register_builtin("save", save_builtin(document.path.clone(), hash.clone()));
+        context.register_builtin("load", load_builtin(document.path.clone(), hash.clone()));
+
+        Self {
+            document,
+            document_hash: hash,
+            context,
+            pc: 0,
+            position: Default::default(),
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+
+    /// Make a builtin available to scripts, replacing any existing builtin with the same name
+    pub fn register_builtin(&mut self, name: &str, builtin: impl Builtin + 'static) {
+        self.context.register_builtin(name, builtin);
+    }
+
+    pub fn set_io(&mut self, io: impl Io + 'static) {
+        self.context.io = Box::new(io);
+    }
+
+    /// The name of the section which will run on the next step, or `None` once the story is over
+    pub fn current_section(&self) -> Option<&str> {
+        self.document
+            .sections
+            .get_index(self.pc)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Jump to a section, as if a script had run `goto`, leaving any sections which were called
+    pub fn goto(&mut self, section: &str) -> Result<()> {
+        self.pc = self.section_index(section)?;
+        self.position = Default::default();
+        self.context.call_stack.clear();
+        Ok(())
+    }
+
+    fn section_index(&self, section: &str) -> Result<usize> {
+        Ok(self
+            .document
+            .sections
+            .get_index_of(section)
+            .ok_or_else(|| RundownError::UnknownLabel(section.to_owned()))?)
+    }
+
+    /// The rundown code block which a line of the document is in
+    fn code_block_at(&self, line: usize) -> Option<Location<'_>> {
+        for (name, section) in &self.document.sections {
+            let mut code_blocks = 0;
+            for section_block in section {
+                let content = match &section_block.block {
+                    Block::CodeBlock(Some(syntax), content)
+                        if syntax == RUNDOWN_CODE_BLOCK_SYNTAX =>
+                    {
+                        content
+                    }
+                    _ => continue,
+                };
+                code_blocks += 1;
+                match section_block.line {
+                    Some(first) if (first..first + content.lines().count()).contains(&line) => {
+                        return Some(Location {
+                            path: &self.document.path,
+                            section: name,
+                            block: code_blocks,
+                        });
+                    }
+                    _ => (),
+                }
+            }
+        }
+        None
+    }
+
+    /// Save the progress of the story to a file, to be resumed from the start of the next step, or
+    /// from the start of the step which failed if the last one returned an error
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let section = self
+            .current_section()
+            .ok_or_else(|| anyhow!("Cannot save a story which is over"))?;
+        let current;
+        let checkpoint = match &self.context.checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => {
+                current = Checkpoint::new(section, self.position.clone(), &self.context);
+                &current
+            }
+        };
+        save::save(path.as_ref(), &self.document_hash, checkpoint)
+    }
+
+    /// Restore the progress of the story from a file written by `save`
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let (section, position) =
+            save::load(path.as_ref(), &self.document_hash, &mut self.context)?;
+        self.pc = self.section_index(&section)?;
+        self.position = position;
+        self.context.checkpoint = None;
+        Ok(())
+    }
+
+    /// Run the current section, then move on to either the next section, the target of a goto or
+    /// call, or back to the section which called it. Returns false once the story is over.
+    pub fn step(&mut self) -> Result<bool> {
+        let more = self.run_step()?;
+        // A step which fails keeps its checkpoint, so that saving afterwards runs it again
+        self.context.checkpoint = None;
+        Ok(more)
+    }
+
+    fn run_step(&mut self) -> Result<bool> {
+        let (name, section) = match self.document.sections.get_index(self.pc) {
+            Some(section) => section,
+            None => return Ok(false),
+        };
+
+        self.context.checkpoint = Some(Checkpoint::new(name, self.position.clone(), &self.context));
+        let Position {
+            block: start,
+            mut code_blocks,
+            mut conditions,
+        } = std::mem::take(&mut self.position);
+        let mut returned = false;
+        // Whether the block before was `<!-- menu -->`
+        let mut menu_next = false;
+        for (index, SectionBlock { block, line }) in section.iter().enumerate().skip(start) {
+            let shown = conditions
+                .last()
+                .is_none_or(|&(_, outer, inner)| outer && inner);
+            match condition_marker(block) {
+                Some(ConditionMarker::If(condition)) => {
+                    // Conditions in hidden blocks may refer to variables which do not exist yet
+                    let holds = shown
+                        && evaluate_condition(&condition, &mut self.context).with_context(
+                            || format!("In condition `{}` in section `{}`", condition, name),
+                        )?;
+                    conditions.push((condition, shown, holds));
+                    continue;
+                }
+                Some(ConditionMarker::Else) => {
+                    let (condition, outer, inner) = conditions
+                        .pop()
+                        .ok_or(RundownError::UnmatchedConditionMarker("else"))?;
+                    conditions.push((condition, outer, !inner));
+                    continue;
+                }
+                Some(ConditionMarker::EndIf) => {
+                    conditions
+                        .pop()
+                        .ok_or(RundownError::UnmatchedConditionMarker("endif"))?;
+                    continue;
+                }
+                None => (),
+            }
+            if is_menu_marker(block) {
+                menu_next = shown;
+                continue;
+            }
+            let is_menu = std::mem::take(&mut menu_next);
+
+            match block {
+                Block::CodeBlock(Some(syntax), content) if syntax == RUNDOWN_CODE_BLOCK_SYNTAX => {
+                    code_blocks += 1;
+                    if !shown {
+                        continue;
+                    }
+                    let location = Location {
+                        path: &self.document.path,
+                        section: name,
+                        block: code_blocks,
+                    };
+
+                    let statements = ast::parse(content, line.unwrap_or(1))
+                        .map_err(|e| annotate(e, &self.document.source, &location))?;
+                    let res = self.context.eval(&statements).map_err(|e| {
+                        // Errors in functions point into the block which defined the function
+                        let location = error_line(&e)
+                            .and_then(|line| self.code_block_at(line))
+                            .unwrap_or(location);
+                        annotate(e, &self.document.source, &location)
+                    })?;
+
+                    match res {
+                        StatementResult::Goto(label) => {
+                            self.pc = self.section_index(&label)?;
+                            match self.context.resume.take() {
+                                // A goto from `load` carries on from wherever the save was made,
+                                // with the call stack it restored
+                                Some(position) => self.position = position,
+                                // Otherwise it leaves any sections which were called, rather than
+                                // returning to them once the section it jumps to is over
+                                None => self.context.call_stack.clear(),
+                            }
+                            return Ok(true);
+                        }
+                        StatementResult::Call(label) => {
+                            if self.context.call_stack.len() >= MAX_CALL_DEPTH {
+                                bail!(RundownError::CallDepthExceeded(MAX_CALL_DEPTH));
+                            }
+                            let position = Position {
+                                block: index + 1,
+                                code_blocks,
+                                conditions,
+                            };
+                            let target = self.section_index(&label)?;
+                            self.context.call_stack.push((self.pc, position));
+                            self.pc = target;
+                            return Ok(true);
+                        }
+                        StatementResult::ReturnSection => {
+                            returned = true;
+                            break;
+                        }
+                        _ => (),
+                    }
+                }
+                _ if !shown => (),
+                _ => {
+                    let block = render_block(block, &mut self.context)
+                        .with_context(|| format!("In section `{}`", name))?;
+                    if let Some(menu) = Menu::from_block(&block).filter(|_| is_menu) {
+                        self.context.io.write_markdown(&menu.to_markdown())?;
+                        let section = loop {
+                            let input = self.context.io.read_line()?;
+                            match menu.choose(&input) {
+                                Ok(section) => break section,
+                                Err(message) => self.context.io.write_markdown(&message)?,
+                            }
+                        };
+                        self.pc = self.section_index(section)?;
+                        self.context.call_stack.clear();
+                        return Ok(true);
+                    }
+
+                    let content = generate_markdown(vec![block]);
+                    self.context.io.write_markdown(&content)?;
+                }
+            }
+        }
+
+        if returned && self.context.call_stack.is_empty() {
+            bail!(RundownError::ReturnSectionOutsideCall);
+        }
+        // Returning early leaves the rest of the section, including any `<!-- endif -->`, unread
+        if let (false, Some((condition, _, _))) = (returned, conditions.pop()) {
+            bail!(RundownError::UnclosedCondition(condition));
+        }
+
+        match self.context.call_stack.pop() {
+            Some((pc, position)) => {
+                self.pc = pc;
+                self.position = position;
+            }
+            None => self.pc += 1,
+        }
+        Ok(self.pc < self.document.sections.len())
+    }
+
+    /// Run until the end of the story
+    pub fn run(&mut self) -> Result<()> {
+        while self.step()? {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::io::Scripted;
+    use crate::save::slot_path;
+
+    #[test]
+    fn steps_through_sections_and_gotos() {
+        let document = Document::parse(
+            "# Start\n\nHello\n\n```rundown\nif (read() == \"skip\") { goto \"end\"; }\n```\n\n# Middle\n\nMiddle\n\n# End\n\n```rundown\nprint(greet());\n```\n",
+        );
+        let io = Scripted::new(vec!["skip".to_owned()]);
+        let mut interpreter = Interpreter::new(document);
+        interpreter.set_io(io.clone());
+        interpreter.register_builtin("greet", |_: &mut Context, _: &[Value]| {
+            Ok(ExpressionResult::Value(Value::Str("Goodbye".to_owned())))
+        });
+
+        assert_eq!(interpreter.current_section(), Some("intro"));
+        assert!(interpreter.step().unwrap());
+        assert_eq!(interpreter.current_section(), Some("start"));
+        assert!(interpreter.step().unwrap());
+        assert_eq!(interpreter.current_section(), Some("end"));
+        assert!(!interpreter.step().unwrap());
+        assert_eq!(interpreter.current_section(), None);
+        assert_eq!(io.output(), "Hello\nGoodbye\n");
+    }
+
+    #[test]
+    fn saves_and_loads_progress() {
+        let source = "# Start\n\n```rundown\nlet global gold = 1;\nfun count() {\n    let static n = 0;\n    n += 1;\n    return n;\n}\n```\n\n# Shop\n\n```rundown\ngold += 1;\nprint(\"${gold} ${count()}\");\ngoto \"shop\";\n```\n";
+        let path = std::env::temp_dir().join(format!("rundown-test-{}.json", std::process::id()));
+
+        let io = Scripted::new(vec![]);
+        let mut interpreter = Interpreter::new(Document::parse(source));
+        interpreter.set_io(io.clone());
+        for _ in 0..5 {
+            interpreter.step().unwrap();
+        }
+        interpreter.save(&path).unwrap();
+        assert_eq!(io.output(), "2 1\n3 2\n4 3\n");
+
+        let io = Scripted::new(vec![]);
+        let mut interpreter = Interpreter::new(Document::parse(source));
+        interpreter.set_io(io.clone());
+        interpreter.load(&path).unwrap();
+        assert_eq!(interpreter.current_section(), Some("shop"));
+        interpreter.step().unwrap();
+        assert_eq!(io.output(), "5 4\n");
+
+        let mut interpreter = Interpreter::new(Document::parse(&format!("{}\nChanged", source)));
+        let error = interpreter.load(&path).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RundownError>(),
+            Some(RundownError::StaleSave(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saves_and_loads_the_call_stack() {
+        let block = "let choice = read();\nif (choice == \"save\") { save(\"slot\"); }\nif (choice == \"load\") { load(\"slot\"); }";
+        let source = format!(
+            "# Start\n\n```rundown\n{}\ncall \"shop\";\n```\n\nBack\n\n```rundown\ngoto \"end\";\n```\n\n# Shop\n\n```rundown\n{}\n```\n\nShop\n\n# End\n",
+            block, block
+        );
+        let story = std::env::temp_dir().join(format!("rundown-calls-{}.md", std::process::id()));
+        let slot = slot_path(&story, "slot").unwrap();
+        let mut document = Document::parse(&source);
+        document.path = story;
+        let run = |inputs: &[&str]| {
+            let io = Scripted::new(inputs.iter().map(|input| input.to_string()));
+            let mut interpreter = Interpreter::new(document.clone());
+            interpreter.set_io(io.clone());
+            (interpreter, io)
+        };
+
+        // Saving in a called section returns to the caller once the save is loaded
+        run(&["stay", "save"]).0.run().unwrap();
+        let (mut interpreter, io) = run(&["stay"]);
+        interpreter.load(&slot).unwrap();
+        assert_eq!(interpreter.current_section(), Some("shop"));
+        interpreter.run().unwrap();
+        assert_eq!(io.output(), "Shop\nBack\n");
+
+        // Loading from a script replaces the call stack along with everything else
+        run(&["save", "stay"]).0.run().unwrap();
+        let (mut interpreter, _) = run(&["stay", "load"]);
+        interpreter.step().unwrap();
+        interpreter.step().unwrap();
+        assert_eq!(interpreter.current_section(), Some("shop"));
+        interpreter.step().unwrap();
+        assert_eq!(interpreter.current_section(), Some("start"));
+        assert!(interpreter.context.call_stack.is_empty());
+        std::fs::remove_file(&slot).unwrap();
+    }
+
+    #[test]
+    fn saves_do_not_repeat_what_the_step_did_before_saving() {
+        let source = "# Start\n\n```rundown\nlet global gold = 0;\n```\n\n# Shop\n\n```rundown\ngold += 10;\nsave(\"slot\");\nprint(\"${gold}\");\nlet answer = read();\n```\n";
+        let story = std::env::temp_dir().join(format!("rundown-gold-{}.md", std::process::id()));
+        let slot = slot_path(&story, "slot").unwrap();
+        let exit = story.with_extension("exit.json");
+        let mut document = Document::parse(source);
+        document.path = story;
+
+        // Running out of input stops the story part way through the shop
+        let mut interpreter = Interpreter::new(document.clone());
+        interpreter.set_io(Scripted::new(vec![]));
+        interpreter.run().unwrap_err();
+        interpreter.save(&exit).unwrap();
+
+        for path in &[&slot, &exit] {
+            let io = Scripted::new(vec!["done".to_owned()]);
+            let mut interpreter = Interpreter::new(document.clone());
+            interpreter.set_io(io.clone());
+            interpreter.load(path).unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(io.output(), "10\n");
+        }
+        std::fs::remove_file(&slot).unwrap();
+        std::fs::remove_file(&exit).unwrap();
+    }
+
+    #[test]
+    fn errors_keep_their_type_under_the_diagnostic() {
+        let mut interpreter = Interpreter::new(Document::parse(
+            "# Start\n\n```rundown\nlet x = 1 / 0;\n```\n",
+        ));
+        let error = interpreter.run().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RundownError>(),
+            Some(RundownError::DivisionByZero(_))
+        ));
+        assert!(error.to_string().contains("--> <document>:4:9"));
+
+        // Code blocks in list items are not counted as the rundown code blocks of the section
+        let mut interpreter = Interpreter::new(Document::parse(
+            "# Start\n\n1. Example\n\n   ```rundown\n   let x = 1;\n   ```\n\n```rundown\nlet x = 1 / 0;\n```\n",
+        ));
+        interpreter.set_io(Scripted::new(vec![]));
+        let error = interpreter.run().unwrap_err();
+        assert!(error.to_string().contains("--> <document>:10:9"));
+
+        // Errors in a function point at the block which defined it, not the one which called it
+        let mut interpreter = Interpreter::new(Document::parse(
+            "# Start\n\n```rundown\nfun half(x) {\n    return x / 0;\n}\n```\n\n# End\n\n```rundown\nlet a = 1;\n```\n\n```rundown\nhalf(1);\n```\n",
+        ));
+        interpreter.set_io(Scripted::new(vec![]));
+        let error = interpreter.run().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("--> <document>:5:12 (section `start`, code block 1)"));
+    }
+
+    #[test]
+    fn document_errors_are_reported_as_rundown_errors() {
+        let error = |source: &str| {
+            let mut interpreter = Interpreter::new(Document::parse(source));
+            interpreter.set_io(Scripted::new(vec![]));
+            interpreter.run().unwrap_err()
+        };
+        assert!(matches!(
+            error("# Start\n\n```rundown\ngoto \"nowhere\";\n```\n").downcast_ref(),
+            Some(RundownError::UnknownLabel(label)) if label == "nowhere"
+        ));
+        assert!(matches!(
+            error("# Start\n\n```rundown\nreturn_section;\n```\n").downcast_ref(),
+            Some(RundownError::ReturnSectionOutsideCall)
+        ));
+        assert!(matches!(
+            error("# Start\n\nAgain? {{ restart() }}\n").downcast_ref(),
+            Some(RundownError::GotoInTemplate(label)) if label == "intro"
+        ));
+        assert!(matches!(
+            error("# Start\n\n<!-- endif -->\n").downcast_ref(),
+            Some(RundownError::UnmatchedConditionMarker("endif"))
+        ));
+        assert!(matches!(
+            error("# Start\n\n<!-- if true -->\n\nOops\n").downcast_ref(),
+            Some(RundownError::UnclosedCondition(condition)) if condition == "true"
+        ));
+
+        let path =
+            std::env::temp_dir().join(format!("rundown-version-{}.json", std::process::id()));
+        std::fs::write(&path, "{\"version\": 1}").unwrap();
+        let error = Interpreter::new(Document::parse("# Start\n"))
+            .load(&path)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RundownError::UnsupportedSaveVersion { version, .. }) if version == "1"
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn conditions_show_and_hide_blocks() {
+        let source = "# Hall\n\n```rundown\nlet global has_key = read() == \"yes\";\n```\n\n<!-- if has_key -->\n\nUnlocked\n\n<!-- if !has_key -->\n\nNever\n\n<!-- endif -->\n\n<!-- else -->\n\nLocked\n\n```rundown\nprint(\"Knock\");\n```\n\n<!-- endif -->\n\nDone\n";
+        for (answer, expected) in &[("yes", "Unlocked\nDone\n"), ("no", "Locked\nKnock\nDone\n")] {
+            let io = Scripted::new(vec![answer.to_string()]);
+            let mut interpreter = Interpreter::new(Document::parse(source));
+            interpreter.set_io(io.clone());
+            interpreter.run().unwrap();
+            assert_eq!(io.output(), *expected);
+        }
+
+        // Markdown inside a condition is left alone, rather than being taken as emphasis
+        let source = "```rundown\nlet global a_b = true;\nlet global c_d = read() == \"yes\";\n```\n\n<!-- if a_b && c_d -->\n\nOpen\n\n<!-- endif -->\n\n<!-- if 2 * 3 > 5 * 1 -->\n\nBigger\n\n<!-- endif -->\n";
+        for (answer, expected) in &[("yes", "Open\nBigger\n"), ("no", "Bigger\n")] {
+            let io = Scripted::new(vec![answer.to_string()]);
+            let mut interpreter = Interpreter::new(Document::parse(source));
+            interpreter.set_io(io.clone());
+            interpreter.run().unwrap();
+            assert_eq!(io.output(), *expected);
+        }
+
+        let mut interpreter = Interpreter::new(Document::parse("<!-- if true -->\n\nOops\n"));
+        interpreter.set_io(Scripted::new(vec![]));
+        assert!(interpreter.run().is_err());
+    }
+
+    #[test]
+    fn menus_jump_to_the_chosen_section() {
+        let source = "# Start\n\nWhere now?\n\n<!-- menu -->\n\n1. [Go north](#north)\n1. [Go south](#south)\n\nNever shown\n\n# North\n\nCold\n\n# South\n\nWarm\n";
+        let io = Scripted::new(vec!["west".to_owned(), "south".to_owned()]);
+        let mut interpreter = Interpreter::new(Document::parse(source));
+        interpreter.set_io(io.clone());
+        interpreter.run().unwrap();
+        assert_eq!(
+            io.output(),
+            "Where now?\n1. Go north\n2. Go south\nPlease choose an option by its number, from 1 to 2, or its name\nWarm\n"
+        );
+
+        // Choosing from a menu in a called section leaves it, like goto
+        let source = "# Start\n\n```rundown\ncall \"map\";\n```\n\nNever shown\n\n# Map\n\n<!-- menu -->\n\n1. [Go north](#north)\n\n# North\n\nCold\n";
+        let io = Scripted::new(vec!["1".to_owned()]);
+        let mut interpreter = Interpreter::new(Document::parse(source));
+        interpreter.set_io(io.clone());
+        while interpreter.current_section() != Some("north") {
+            interpreter.step().unwrap();
+        }
+        assert!(interpreter.context.call_stack.is_empty());
+        interpreter.run().unwrap();
+        assert_eq!(io.output(), "1. Go north\nCold\n");
+
+        // Without the marker a list of links is shown like any other list
+        let source = "# Start\n\n- [North](#north)\n- [South](#south)\n\n# North\n\nCold\n\n# South\n\nWarm\n";
+        let io = Scripted::new(vec![]);
+        let mut interpreter = Interpreter::new(Document::parse(source));
+        interpreter.set_io(io.clone());
+        interpreter.run().unwrap();
+        assert_eq!(io.output(), "- North\n- South\nCold\nWarm\n");
+    }
+
+    #[test]
+    fn calls_return_after_the_calling_code_block() {
+        let source = "# Start\n\n```rundown\ncall \"shop\";\nprint(\"Skipped\");\n```\n\nBack\n\n```rundown\ngoto \"end\";\n```\n\n# Shop\n\nShop\n\n```rundown\nif (true) { return_section; }\n```\n\nClosed\n\n# End\n\nEnd\n";
+        let io = Scripted::new(vec![]);
+        let mut interpreter = Interpreter::new(Document::parse(source));
+        interpreter.set_io(io.clone());
+        interpreter.run().unwrap();
+        assert_eq!(io.output(), "Shop\nBack\nEnd\n");
+
+        // Leaving a called section with goto does not return to it, so the calls do not pile up
+        let source = "# Start\n\n```rundown\nlet global n = 0;\n```\n\n# Town\n\n```rundown\nn += 1;\nif (n > 100) { goto \"end\"; }\ncall \"shop\";\n```\n\nNever shown\n\n# Shop\n\n```rundown\ngoto \"town\";\n```\n\n# End\n\n```rundown\nprint(\"${n}\");\n```\n";
+        let io = Scripted::new(vec![]);
+        let mut interpreter = Interpreter::new(Document::parse(source));
+        interpreter.set_io(io.clone());
+        interpreter.run().unwrap();
+        assert_eq!(io.output(), "101\n");
+
+        let io = Scripted::new(vec![]);
+        let mut interpreter = Interpreter::new(Document::parse(source));
+        interpreter.set_io(io.clone());
+        while interpreter.current_section() != Some("shop") {
+            interpreter.step().unwrap();
+        }
+        interpreter.goto("end").unwrap();
+        assert!(interpreter.context.call_stack.is_empty());
+        interpreter.run().unwrap();
+        assert_eq!(io.output(), "1\n");
+
+        let mut interpreter = Interpreter::new(Document::parse(
+            "# Loop\n\n```rundown\ncall \"loop\";\n```\n",
+        ));
+        let error = interpreter.run().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RundownError>(),
+            Some(RundownError::CallDepthExceeded(_))
+        ));
+
+        let mut interpreter = Interpreter::new(Document::parse(
+            "# Start\n\n```rundown\nreturn_section;\n```\n",
+        ));
+        assert!(interpreter.run().is_err());
+
+        let mut interpreter = Interpreter::new(Document::parse(
+            "# Start\n\n```rundown\ncall \"nowhere\";\n```\n",
+        ));
+        let error = interpreter.run().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<RundownError>(),
+            Some(RundownError::UnknownLabel(_))
+        ));
+        assert!(interpreter.context.call_stack.is_empty());
+    }
+}
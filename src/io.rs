@@ -0,0 +1,124 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use crate::markdown::{plain_text, print_markdown};
+
+/// Where a story reads its input from and writes its output to, so that it can be run somewhere
+/// other than a terminal
+pub trait Io {
+    /// Read a line of input, without the trailing newline
+    fn read_line(&mut self) -> Result<String>;
+
+    fn write_markdown(&mut self, markdown: &str) -> Result<()>;
+
+    /// Clear everything which has been written so far
+    fn clear(&mut self) -> Result<()>;
+
+    fn sleep(&mut self, duration: Duration) -> Result<()>;
+}
+
+/// Reaching the end of input, such as when Ctrl-D is pressed, is an error which stops the story.
+/// Returning an empty answer instead would leave a story which asks again until it gets a valid
+/// answer waiting forever.
+fn read_line_from(reader: &mut impl BufRead) -> Result<String> {
+    let mut buffer = String::new();
+    if reader.read_line(&mut buffer)? == 0 {
+        bail!("Reached the end of input");
+    }
+    Ok(buffer.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// Reads from stdin, and renders Markdown to stdout with terminal formatting
+#[derive(Debug, Default)]
+pub struct Terminal;
+
+impl Io for Terminal {
+    fn read_line(&mut self) -> Result<String> {
+        read_line_from(&mut io::stdin().lock())
+    }
+
+    fn write_markdown(&mut self, markdown: &str) -> Result<()> {
+        print_markdown(markdown)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        // Clear the screen and move the cursor to the top left
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1B[2J\x1B[1;1H")?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn sleep(&mut self, duration: Duration) -> Result<()> {
+        thread::sleep(duration);
+        Ok(())
+    }
+}
+
+/// Reads input from a fixed list of lines and records output as plain text, for testing stories.
+/// Sleeping does nothing, and clearing is ignored so that the whole output is kept.
+///
+/// Clones share their output, so keep a clone to inspect what was written after giving one to an
+/// interpreter.
+#[derive(Debug, Default, Clone)]
+pub struct Scripted {
+    input: VecDeque<String>,
+    output: Rc<RefCell<String>>,
+}
+
+impl Scripted {
+    pub fn new<I: IntoIterator<Item = String>>(input: I) -> Self {
+        Self {
+            input: input.into_iter().collect(),
+            output: Default::default(),
+        }
+    }
+
+    pub fn output(&self) -> String {
+        self.output.borrow().clone()
+    }
+}
+
+impl Io for Scripted {
+    /// Running out of input is an error, rather than blocking forever
+    fn read_line(&mut self) -> Result<String> {
+        match self.input.pop_front() {
+            Some(line) => Ok(line),
+            None => bail!("Ran out of input"),
+        }
+    }
+
+    fn write_markdown(&mut self, markdown: &str) -> Result<()> {
+        let mut output = self.output.borrow_mut();
+        output.push_str(plain_text(markdown).trim_end());
+        output.push('\n');
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sleep(&mut self, _duration: Duration) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terminal_input_ends_with_an_error() {
+        let mut input = &b"yes\r\nno"[..];
+        assert_eq!(read_line_from(&mut input).unwrap(), "yes");
+        assert_eq!(read_line_from(&mut input).unwrap(), "no");
+        assert!(read_line_from(&mut input).is_err());
+    }
+}
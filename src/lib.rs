@@ -0,0 +1,34 @@
+//! Rundown runs Markdown documents as interactive stories. Prose is rendered as it is reached, and
+//! `rundown` code blocks are run to read input, keep track of state, and jump between sections.
+//!
+//! ```no_run
+//! use rundown::{Document, Interpreter};
+//!
+//! let document = Document::open("story.md")?;
+//! let mut interpreter = Interpreter::new(document);
+//! while interpreter.step()? {}
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+#[macro_use]
+extern crate pest_derive;
+
+mod ast;
+mod builtins;
+mod check;
+mod diagnostic;
+mod document;
+mod error;
+mod eval;
+mod interpreter;
+mod io;
+mod markdown;
+mod menu;
+mod save;
+mod template;
+
+pub use crate::document::Document;
+pub use crate::error::RundownError;
+pub use crate::eval::{Builtin, Context, ExpressionResult, Value};
+pub use crate::interpreter::Interpreter;
+pub use crate::io::{Io, Scripted, Terminal};
@@ -1,63 +1,349 @@
-#[macro_use]
-extern crate pest_derive;
-
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
 
-use ::markdown::{generate_markdown, Block};
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Context as _, Error, Result};
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
-mod ast;
-mod builtins;
-mod eval;
-mod markdown;
-
-use crate::builtins::builtins;
-use crate::eval::{Context, StatementResult};
-use crate::markdown::{construct_index, print_markdown};
-
-const RUNDOWN_CODE_BLOCK_SYNTAX: &str = "rundown";
+use rundown::{Document, Interpreter, Scripted};
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "rundown", about = "Run your markdown adventure!")]
+#[structopt(
+    name = "rundown",
+    about = "Run your markdown adventure!",
+    setting = AppSettings::ArgRequiredElseHelp,
+    setting = AppSettings::ArgsNegateSubcommands
+)]
 struct Opt {
     /// Input file
     #[structopt(parse(from_os_str))]
-    input: PathBuf,
-}
-
-fn main() -> Result<()> {
-    let opt = Opt::from_args();
-    let input = fs::read_to_string(&opt.input)?;
-    let section_index = construct_index(&input);
-
-    let mut context = Context::new(builtins());
-
-    let mut pc = 0;
-    'outer: while let Some((_name, section)) = section_index.get_index(pc) {
-        for block in section {
-            match block {
-                Block::CodeBlock(Some(syntax), content) if syntax == RUNDOWN_CODE_BLOCK_SYNTAX => {
-                    let statements = ast::parse(content)?;
-                    let res = context.eval(&statements)?;
-
-                    if let StatementResult::Goto(label) = res {
-                        pc = section_index
-                            .get_index_of(&label)
-                            .ok_or_else(|| anyhow!("Tried to goto section that does not exist"))?;
-                        continue 'outer;
-                    }
-                }
-                _ => {
-                    let content = generate_markdown(vec![block.clone()]);
-                    print_markdown(&content)?;
-                }
+    input: Option<PathBuf>,
+
+    /// Use arbitrary precision for int arithmetic instead of failing on overflow
+    #[structopt(long, global = true)]
+    big_integers: bool,
+
+    /// Seed for the random number generator, to make runs reproducible
+    #[structopt(long, global = true)]
+    seed: Option<u64>,
+
+    /// Carry on from a file written by `save` or --save-on-exit
+    #[structopt(long, parse(from_os_str))]
+    resume: Option<PathBuf>,
+
+    /// Save progress when the story stops, to the --resume file or `<input>.autosave.json`
+    #[structopt(long)]
+    save_on_exit: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Validate every code block and goto target without running the script
+    Check {
+        /// Input file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Run the script with canned input and compare its output against an expected transcript
+    ///
+    /// Without --input and --expect, every `*.input` file in a directory named after the script is
+    /// run against the `*.expected` file with the same name, so `story.md` is tested by
+    /// `story/win.input` and `story/win.expected`.
+    Test {
+        /// Input file
+        #[structopt(parse(from_os_str))]
+        story: PathBuf,
+
+        /// File with a line of input for each `read()`
+        #[structopt(long = "input", parse(from_os_str), requires = "expect")]
+        answers: Option<PathBuf>,
+
+        /// File with the expected output
+        #[structopt(long, parse(from_os_str), requires = "answers")]
+        expect: Option<PathBuf>,
+    },
+}
+
+/// Random numbers are seeded in tests, so that transcripts are reproducible
+const DEFAULT_TEST_SEED: u64 = 0;
+
+fn interpreter(document: Document, opt: &Opt) -> Interpreter {
+    let mut interpreter = Interpreter::new(document);
+    let context = interpreter.context_mut();
+    context.big_integers = opt.big_integers;
+    if let Some(seed) = opt.seed {
+        context.seed_rng(seed);
+    }
+    interpreter
+}
+
+/// Run a story with the answers, returning whether the output matched the expected transcript
+fn test_transcript(document: &Document, opt: &Opt, answers: &Path, expect: &Path) -> Result<bool> {
+    let input = fs::read_to_string(answers)
+        .with_context(|| format!("Could not read {}", answers.display()))?;
+    let expected = fs::read_to_string(expect)
+        .with_context(|| format!("Could not read {}", expect.display()))?;
+
+    let io = Scripted::new(input.lines().map(str::to_owned));
+    let mut interpreter = interpreter(document.clone(), opt);
+    if opt.seed.is_none() {
+        interpreter.context_mut().seed_rng(DEFAULT_TEST_SEED);
+    }
+    interpreter.set_io(io.clone());
+    let result = interpreter.run();
+    let actual = io.output();
+
+    let expected_lines: Vec<_> = expected.trim_end().lines().collect();
+    let actual_lines: Vec<_> = actual.trim_end().lines().collect();
+    let matched = result.is_ok() && expected_lines == actual_lines;
+    if matched {
+        println!("ok      {}", answers.display());
+    } else {
+        println!("FAILED  {}", answers.display());
+        if let Err(error) = result {
+            println!("  error: {}", report(&error));
+        }
+        if let Some(difference) = first_difference(&expected_lines, &actual_lines) {
+            println!("{}", difference);
+        }
+    }
+    Ok(matched)
+}
+
+/// Lines of the transcript shown before the first line which differs
+const DIFFERENCE_CONTEXT: usize = 2;
+
+/// Show where the output first strays from the transcript, since after an extra or missing line
+/// every line which follows would differ too
+fn first_difference(expected: &[&str], actual: &[&str]) -> Option<String> {
+    let i = (0..expected.len().max(actual.len())).find(|&i| expected.get(i) != actual.get(i))?;
+    let line = |prefix, line: Option<&&str>| {
+        format!(
+            "  {:>4} {} {}",
+            i + 1,
+            prefix,
+            line.unwrap_or(&"<end of output>")
+        )
+    };
+    let mut ret: Vec<_> = (i.saturating_sub(DIFFERENCE_CONTEXT)..i)
+        .map(|j| format!("  {:>4}   {}", j + 1, expected[j]))
+        .collect();
+    ret.push(line('-', expected.get(i)));
+    ret.push(line('+', actual.get(i)));
+    Some(ret.join("\n"))
+}
+
+/// Pairs of answers and expected transcripts in the directory next to a story
+fn transcripts(story: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let directory = story.with_extension("");
+    let entries = fs::read_dir(&directory)
+        .with_context(|| format!("Could not read test directory {}", directory.display()))?;
+    let mut ret = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() == Some("input".as_ref()) {
+            let expect = path.with_extension("expected");
+            if !expect.exists() {
+                bail!("{} has no matching {}", path.display(), expect.display());
             }
+            ret.push((path, expect));
         }
+    }
+    if ret.is_empty() {
+        bail!("No *.input files found in {}", directory.display());
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+fn test(story: &Path, answers: Option<&Path>, expect: Option<&Path>, opt: &Opt) -> Result<()> {
+    let document = Document::open(story)?;
+    let cases = match (answers, expect) {
+        (Some(answers), Some(expect)) => vec![(answers.to_owned(), expect.to_owned())],
+        _ => transcripts(story)?,
+    };
 
-        pc += 1;
+    let mut failures = 0;
+    for (answers, expect) in &cases {
+        if !test_transcript(&document, opt, answers, expect)? {
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        bail!("{} of {} transcripts did not match", failures, cases.len());
     }
+    Ok(())
+}
 
+/// Print every problem `Document::check` finds, failing if there are any
+fn check(input: &Path) -> Result<()> {
+    let problems = Document::open(input)?.check();
+    for problem in &problems {
+        eprintln!("{}\n", problem);
+    }
+
+    if !problems.is_empty() {
+        bail!("Found {} problem(s) in {}", problems.len(), input.display());
+    }
+
+    println!("{}: no problems found", input.display());
     Ok(())
 }
+
+/// The message of an error followed by its causes, like anyhow prints them, but leaving out causes
+/// which a message above them already includes, such as the error under a rendered diagnostic
+fn report(error: &Error) -> String {
+    let messages: Vec<_> = error.chain().map(ToString::to_string).collect();
+    let causes: Vec<_> = messages
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(i, cause)| {
+            !messages[..*i]
+                .iter()
+                .any(|message| message.contains(*cause))
+        })
+        .map(|(_, cause)| cause)
+        .collect();
+
+    let mut report = messages[0].clone();
+    match causes.as_slice() {
+        [] => (),
+        [cause] => report.push_str(&format!("\n\nCaused by:\n    {}", cause)),
+        causes => {
+            report.push_str("\n\nCaused by:");
+            for (i, cause) in causes.iter().enumerate() {
+                report.push_str(&format!("\n    {}: {}", i, cause));
+            }
+        }
+    }
+    report
+}
+
+fn run(opt: &Opt) -> Result<()> {
+    match (&opt.command, &opt.input) {
+        (Some(Command::Check { input }), _) => check(input),
+        (
+            Some(Command::Test {
+                story,
+                answers,
+                expect,
+            }),
+            _,
+        ) => test(story, answers.as_deref(), expect.as_deref(), opt),
+        (None, Some(input)) => {
+            let mut interpreter = interpreter(Document::open(input)?, opt);
+            if let Some(resume) = &opt.resume {
+                interpreter.load(resume)?;
+            }
+            let result = interpreter.run();
+            // There is nothing to resume once the story is over
+            if opt.save_on_exit && interpreter.current_section().is_some() {
+                let path = opt
+                    .resume
+                    .clone()
+                    .unwrap_or_else(|| input.with_extension("autosave.json"));
+                interpreter.save(&path)?;
+                eprintln!("Saved progress to {}", path.display());
+            }
+            result
+        }
+        (None, None) => bail!("No input file provided"),
+    }
+}
+
+fn main() {
+    if let Err(error) = run(&Opt::from_args()) {
+        eprintln!("Error: {}", report(&error));
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_leave_out_causes_already_in_the_message() {
+        let error = Interpreter::new(Document::parse(
+            "# Start\n\n```rundown\nlet x = 1 / 0;\n```\n",
+        ))
+        .run()
+        .unwrap_err();
+        let reported = report(&error);
+        assert_eq!(reported.matches("Division by zero").count(), 1);
+        assert!(!reported.contains("Caused by"));
+
+        let error = anyhow::anyhow!("inner").context("outer");
+        assert_eq!(report(&error), "outer\n\nCaused by:\n    inner");
+    }
+
+    #[test]
+    fn first_difference_shows_the_lines_before_it() {
+        let expected = ["a", "b", "c", "d", "e"];
+        let actual = ["a", "b", "c", "inserted", "d", "e"];
+        assert_eq!(
+            first_difference(&expected, &actual).unwrap(),
+            "     2   b\n     3   c\n     4 - d\n     4 + inserted"
+        );
+        assert_eq!(
+            first_difference(&expected, &expected[..4]).unwrap(),
+            "     3   c\n     4   d\n     5 - e\n     5 + <end of output>"
+        );
+        assert_eq!(first_difference(&expected, &expected), None);
+    }
+
+    #[test]
+    fn transcripts_next_to_a_story_are_tested() {
+        let directory = std::env::temp_dir().join(format!("rundown-test-{}", std::process::id()));
+        let story = directory.join("story.md");
+        let cases_directory = directory.join("story");
+        fs::create_dir_all(&cases_directory).unwrap();
+        fs::write(
+            &story,
+            "# Start\n\nHello **{{ read() }}**\n\n- [See the docs](#end)\n\n# End\n\nBye\n",
+        )
+        .unwrap();
+        fs::write(cases_directory.join("good.input"), "ann\n").unwrap();
+        fs::write(
+            cases_directory.join("good.expected"),
+            "Hello ann\n- See the docs\nBye\n",
+        )
+        .unwrap();
+        fs::write(cases_directory.join("bad.input"), "bob\n").unwrap();
+        fs::write(
+            cases_directory.join("bad.expected"),
+            "Hello ann\n- See the docs\nBye\n",
+        )
+        .unwrap();
+
+        let opt = Opt::from_iter(&["rundown".as_ref(), "test".as_ref(), story.as_os_str()]);
+        let cases = transcripts(&story).unwrap();
+        assert_eq!(
+            cases,
+            vec![
+                (
+                    cases_directory.join("bad.input"),
+                    cases_directory.join("bad.expected")
+                ),
+                (
+                    cases_directory.join("good.input"),
+                    cases_directory.join("good.expected")
+                ),
+            ]
+        );
+        let document = Document::open(&story).unwrap();
+        let results: Vec<_> = cases
+            .iter()
+            .map(|(answers, expect)| test_transcript(&document, &opt, answers, expect).unwrap())
+            .collect();
+        assert_eq!(results, vec![false, true]);
+        let error = test(&story, None, None, &opt).unwrap_err();
+        assert_eq!(error.to_string(), "1 of 2 transcripts did not match");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -6,11 +6,16 @@ use indexmap::IndexMap;
 use lazy_static::lazy_static;
 use markdown::{Block, Span};
 use mdcat::{Environment, ResourceAccess, Settings, TerminalCapabilities, TerminalSize};
-use pulldown_cmark::{Options, Parser as MdParser};
+use pulldown_cmark::{Event, Options, Parser as MdParser, Tag};
 use slugify::slugify;
 use syntect::parsing::SyntaxSet;
 
+use crate::template::{CLOSE, OPEN};
+
+pub const RUNDOWN_CODE_BLOCK_SYNTAX: &str = "rundown";
+
 const INTRO_SECTION: &str = "intro";
+const CODE_FENCE: &str = "```";
 
 lazy_static! {
     static ref MDCAT_SETTINGS: Settings = Settings {
@@ -23,11 +28,12 @@ lazy_static! {
         Environment::for_local_directory(&env::current_dir().unwrap()).unwrap();
 }
 
-fn spans_to_string(spans: &[Span]) -> String {
+pub fn spans_to_string(spans: &[Span]) -> String {
     spans
         .iter()
         .map(|span| match span {
-            Span::Text(ref s) | Span::Code(ref s) | Span::Image(ref s, _, _) => s.to_owned(),
+            Span::Text(ref s) | Span::Code(ref s) => restore_placeholders(s),
+            Span::Image(ref s, _, _) => s.to_owned(),
             Span::Literal(c) => c.to_string(),
             Span::Link(s, _, _) | Span::RefLink(s, _, _) | Span::Emphasis(s) | Span::Strong(s) => {
                 spans_to_string(s)
@@ -38,18 +44,200 @@ fn spans_to_string(spans: &[Span]) -> String {
         .join("")
 }
 
-pub fn construct_index(content: &str) -> IndexMap<String, Vec<Block>> {
-    let mut ret: IndexMap<String, Vec<Block>> = IndexMap::new();
+/// A block of Markdown belonging to a section
+#[derive(Debug, Clone)]
+pub struct SectionBlock {
+    pub block: Block,
+    /// Line in the source file on which the contents of a rundown code block begin
+    pub line: Option<usize>,
+}
+
+pub type Index = IndexMap<String, Vec<SectionBlock>>;
+
+/// HTML comments which only show the blocks between them when a condition holds, and which other
+/// Markdown renderers hide
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionMarker {
+    /// `<!-- if condition -->`
+    If(String),
+    /// `<!-- else -->`
+    Else,
+    /// `<!-- endif -->`
+    EndIf,
+}
+
+/// The text of an HTML comment which is a block of its own, separated from the prose around it by
+/// blank lines
+fn comment(block: &Block) -> Option<String> {
+    let text = match block {
+        Block::Paragraph(spans) => spans_to_string(spans),
+        Block::Raw(text) => restore_placeholders(text),
+        _ => return None,
+    };
+    Some(
+        text.trim()
+            .strip_prefix("<!--")?
+            .strip_suffix("-->")?
+            .trim()
+            .to_owned(),
+    )
+}
+
+pub fn condition_marker(block: &Block) -> Option<ConditionMarker> {
+    let comment = comment(block)?;
+    match comment.as_str() {
+        "else" => Some(ConditionMarker::Else),
+        "endif" => Some(ConditionMarker::EndIf),
+        _ => {
+            let condition = comment.strip_prefix("if")?;
+            if condition.starts_with(char::is_whitespace) {
+                Some(ConditionMarker::If(condition.trim().to_owned()))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// `<!-- menu -->`, which makes the list after it a menu
+pub fn is_menu_marker(block: &Block) -> bool {
+    comment(block).as_deref() == Some("menu")
+}
+
+/// The opening fence of a code block, as its indentation, the number of backticks and its info
+/// string
+fn opening_fence(line: &str) -> Option<(usize, usize, &str)> {
+    let fence = line.trim_start();
+    let backticks = fence.chars().take_while(|&c| c == '`').count();
+    if backticks < CODE_FENCE.len() {
+        return None;
+    }
+    Some((
+        line.len() - fence.len(),
+        backticks,
+        fence[backticks..].trim(),
+    ))
+}
+
+/// Closing fences have at least as many backticks as the opening one, and nothing after them
+fn is_closing_fence(line: &str, backticks: usize) -> bool {
+    let fence = line.trim_start();
+    let count = fence.chars().take_while(|&c| c == '`').count();
+    count >= backticks && fence[count..].trim().is_empty()
+}
+
+/// The markdown tokenizer does not track positions, so find the line a rundown code block from it
+/// begins on by looking for the next fenced block with the same contents, starting from `from`.
+/// Code blocks nested in list items or block quotes are indented or quoted, so they are passed
+/// over rather than being mistaken for the block after them.
+fn rundown_code_block_line(lines: &[&str], from: &mut usize, content: &str) -> Option<usize> {
+    let mut start = *from;
+    while start < lines.len() {
+        let (indent, backticks, info) = match opening_fence(lines[start]) {
+            Some(fence) => fence,
+            None => {
+                start += 1;
+                continue;
+            }
+        };
+        let end = (start + 1..lines.len())
+            .find(|&i| is_closing_fence(lines[i], backticks))
+            .unwrap_or(lines.len());
+
+        // The contents of a fence lose as much indentation as the fence has
+        let body = lines[start + 1..end].iter().map(|line| {
+            let trimmed = line.trim_start_matches(' ');
+            &line[(line.len() - trimmed.len()).min(indent)..]
+        });
+        if info == RUNDOWN_CODE_BLOCK_SYNTAX && body.eq(content.lines()) {
+            *from = end + 1;
+            // Lines are 1-indexed, and the contents start after the fence
+            return Some(start + 2);
+        }
+        start = end + 1;
+    }
+    None
+}
+
+/// Characters which Markdown treats specially within a line. Inside placeholders and HTML comments
+/// they are swapped for private use characters before tokenizing, so that the `_` in
+/// `{{ max_hp }}` or `<!-- if has_key && door_open -->` is not taken as the start of emphasis and
+/// the placeholder or condition stays in one piece.
+const SPECIAL: &str = "\\`*_[]<>!";
+const PROTECTED: u32 = 0xE000;
+
+const COMMENT_OPEN: &str = "<!--";
+const COMMENT_CLOSE: &str = "-->";
+
+fn protect_placeholders(content: &str) -> String {
+    let mut ret = String::with_capacity(content.len());
+    let mut in_fence = false;
+
+    for line in content.split_inclusive('\n') {
+        if line.trim_start().starts_with(CODE_FENCE) {
+            in_fence = !in_fence;
+        }
+        if in_fence {
+            ret.push_str(line);
+            continue;
+        }
+
+        let mut rest = line;
+        while let Some((start, open, close)) = [(OPEN, CLOSE), (COMMENT_OPEN, COMMENT_CLOSE)]
+            .iter()
+            .filter_map(|&(open, close)| rest.find(open).map(|start| (start, open, close)))
+            .min_by_key(|&(start, _, _)| start)
+        {
+            let (before, inner) = rest.split_at(start + open.len());
+            ret.push_str(before);
+            rest = inner;
+            if open == OPEN && before[..start].ends_with('\\') {
+                continue;
+            }
+            if let Some(end) = inner.find(close) {
+                ret.extend(inner[..end].chars().map(|c| match SPECIAL.find(c) {
+                    Some(i) => std::char::from_u32(PROTECTED + i as u32).unwrap(),
+                    None => c,
+                }));
+                rest = &inner[end..];
+            }
+        }
+        ret.push_str(rest);
+    }
+
+    ret
+}
+
+/// Undo `protect_placeholders` in text taken from the tokenized document
+pub fn restore_placeholders(text: &str) -> String {
+    text.chars()
+        .map(|c| match (c as u32).checked_sub(PROTECTED) {
+            Some(i) if (i as usize) < SPECIAL.len() => SPECIAL.as_bytes()[i as usize] as char,
+            _ => c,
+        })
+        .collect()
+}
+
+pub fn construct_index(content: &str) -> Index {
+    let mut ret: Index = IndexMap::new();
+    let lines = content.lines().collect::<Vec<_>>();
+    let mut next_line = 0;
 
     let mut current_section = ret.entry(INTRO_SECTION.to_owned()).or_default();
-    for block in markdown::tokenize(content) {
+    for block in markdown::tokenize(&protect_placeholders(content)) {
         match block {
             Block::Header(spans, _) => {
                 let name = slugify!(&spans_to_string(&spans).to_lowercase());
                 current_section = ret.entry(name).or_default();
             }
             _ => {
-                current_section.push(block.clone());
+                let line = match &block {
+                    Block::CodeBlock(Some(syntax), code) if syntax == RUNDOWN_CODE_BLOCK_SYNTAX => {
+                        rundown_code_block_line(&lines, &mut next_line, code)
+                    }
+                    _ => None,
+                };
+                current_section.push(SectionBlock { block, line });
             }
         }
     }
@@ -70,3 +258,40 @@ pub fn print_markdown(content: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// The text a reader would see once Markdown is rendered, without any formatting. Lists keep their
+/// numbers or bullets, since menus are chosen by number.
+pub fn plain_text(content: &str) -> String {
+    let mut ret = String::new();
+    let mut lists: Vec<Option<u64>> = vec![];
+    for event in MdParser::new(content) {
+        match event {
+            Event::Start(Tag::List(start)) => lists.push(start),
+            Event::End(Tag::List(_)) => {
+                lists.pop();
+            }
+            Event::Start(Tag::Item) => {
+                if !ret.is_empty() && !ret.ends_with('\n') {
+                    ret.push('\n');
+                }
+                ret.push_str(&"  ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(number)) => {
+                        ret.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => ret.push_str("- "),
+                }
+            }
+            Event::Text(text) | Event::Code(text) | Event::Html(text) => ret.push_str(&text),
+            Event::SoftBreak | Event::HardBreak | Event::Rule => ret.push('\n'),
+            Event::End(Tag::Paragraph | Tag::Heading(_) | Tag::CodeBlock(_) | Tag::Item)
+                if !ret.ends_with('\n') =>
+            {
+                ret.push('\n')
+            }
+            _ => (),
+        }
+    }
+    ret
+}
@@ -0,0 +1,164 @@
+use ::markdown::{Block, ListItem, Span};
+
+use crate::markdown::spans_to_string;
+
+/// A list after a `<!-- menu -->` marker whose items are all links to headings, such as
+/// `1. [Look around](#look-around)`, which asks the reader to pick one and jumps to it. Lists of
+/// links without the marker, such as a table of contents, are shown as they are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Menu {
+    /// The text of each option, and the section it links to
+    options: Vec<(String, String)>,
+}
+
+impl Menu {
+    pub fn from_block(block: &Block) -> Option<Self> {
+        let items = match block {
+            Block::OrderedList(items, _) | Block::UnorderedList(items) => items,
+            _ => return None,
+        };
+
+        let options = items
+            .iter()
+            .map(|item| {
+                let spans = match item {
+                    ListItem::Simple(spans) => spans,
+                    _ => return None,
+                };
+                // Allow whitespace around the link, but nothing else
+                let mut spans = spans
+                    .iter()
+                    .filter(|span| !matches!(span, Span::Text(text) if text.trim().is_empty()));
+                match (spans.next(), spans.next()) {
+                    (Some(Span::Link(text, url, _)), None) => {
+                        let section = url.strip_prefix('#')?;
+                        Some((spans_to_string(text), section.to_owned()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        if options.is_empty() {
+            None
+        } else {
+            Some(Self { options })
+        }
+    }
+
+    /// The sections the menu can jump to
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.options.iter().map(|(_, section)| section.as_str())
+    }
+
+    /// The options as a numbered list, without the links
+    pub fn to_markdown(&self) -> String {
+        self.options
+            .iter()
+            .enumerate()
+            .map(|(i, (text, _))| format!("{}. {}", i + 1, text))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Pick an option by its number or its text, returning the section to jump to. The text can be
+    /// shortened to the start of an option, or any part of it, as long as only one option matches.
+    /// Otherwise returns a message asking the reader to try again.
+    pub fn choose(&self, input: &str) -> Result<&str, String> {
+        let input = input.trim().to_lowercase();
+        let retry = || {
+            format!(
+                "Please choose an option by its number, from 1 to {}, or its name",
+                self.options.len()
+            )
+        };
+        if input.is_empty() {
+            return Err(retry());
+        }
+
+        if let Ok(number) = input.parse::<usize>() {
+            return match number.checked_sub(1).and_then(|i| self.options.get(i)) {
+                Some((_, section)) => Ok(section),
+                None => Err(retry()),
+            };
+        }
+
+        let matches: [&dyn Fn(&str) -> bool; 3] = [
+            &|text| text == input,
+            &|text| text.starts_with(&input),
+            &|text| text.contains(&input),
+        ];
+        for matches in &matches {
+            let mut found = self
+                .options
+                .iter()
+                .filter(|(text, _)| matches(&text.to_lowercase()));
+            match (found.next(), found.next()) {
+                (Some((_, section)), None) => return Ok(section),
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "`{}` matches more than one option, please be more specific",
+                        input
+                    ))
+                }
+                (None, _) => (),
+            }
+        }
+
+        Err(retry())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn menu() -> Menu {
+        Menu {
+            options: vec![
+                ("Look out the window".to_owned(), "window".to_owned()),
+                ("Look under the desk".to_owned(), "desk".to_owned()),
+                ("Run".to_owned(), "run".to_owned()),
+            ],
+        }
+    }
+
+    #[test]
+    fn lists_of_links_to_headings_are_menus() {
+        let link = |text: &str, url: &str| {
+            ListItem::Simple(vec![Span::Link(
+                vec![Span::Text(text.to_owned())],
+                url.to_owned(),
+                None,
+            )])
+        };
+        let block = Block::UnorderedList(vec![link("Run", "#run"), link("Hide", "#hide")]);
+        let menu = Menu::from_block(&block).unwrap();
+        assert_eq!(menu.sections().collect::<Vec<_>>(), vec!["run", "hide"]);
+        assert_eq!(menu.to_markdown(), "1. Run\n2. Hide");
+
+        let block = Block::UnorderedList(vec![link("Run", "#run"), link("Docs", "https://x")]);
+        assert_eq!(Menu::from_block(&block), None);
+        let block = Block::UnorderedList(vec![ListItem::Simple(vec![Span::Text("Run".into())])]);
+        assert_eq!(Menu::from_block(&block), None);
+    }
+
+    #[test]
+    fn options_are_chosen_by_number_or_text() {
+        let menu = menu();
+        assert_eq!(menu.choose("2"), Ok("desk"));
+        assert_eq!(menu.choose(" run "), Ok("run"));
+        assert_eq!(menu.choose("look out"), Ok("window"));
+        assert_eq!(menu.choose("DESK"), Ok("desk"));
+    }
+
+    #[test]
+    fn invalid_or_ambiguous_choices_are_rejected() {
+        let menu = menu();
+        assert!(menu.choose("0").is_err());
+        assert!(menu.choose("4").is_err());
+        assert!(menu.choose("").is_err());
+        assert!(menu.choose("look").is_err());
+        assert!(menu.choose("fly").is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as Json};
+
+use crate::ast::{self, StatementKind};
+use crate::error::RundownError;
+use crate::eval::{Builtin, Context, ExpressionResult, FunctionContext, Scope, Value};
+use crate::interpreter::Position;
+
+/// Bumped whenever the format of save files changes, so that old saves are rejected rather than
+/// misread
+const SAVE_VERSION: u64 = 3;
+
+/// The state of a story as a step starts, which is what gets saved. Loading runs the step again
+/// from where it started, so anything the step did before saving is only done once.
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    section: String,
+    position: Position,
+    call_stack: Vec<(usize, Position)>,
+    globals: Scope,
+    functions: HashMap<String, FunctionContext>,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(section: &str, position: Position, context: &Context) -> Self {
+        Self {
+            section: section.to_owned(),
+            position,
+            call_stack: context.call_stack.clone(),
+            globals: context.global_variables.clone(),
+            functions: context.function_contexts.clone(),
+        }
+    }
+}
+
+/// The progress of a story, everything needed to carry on from the start of a step
+#[derive(Debug, Serialize, Deserialize)]
+struct Save {
+    version: u64,
+    /// Hash of the story the save was made from, since sections and functions may have changed
+    document_hash: String,
+    section: String,
+    /// Where in the section to carry on from, if the step started after a `call` returned
+    position: Position,
+    /// Sections to return to once the saved one ends, if it was reached by `call`
+    call_stack: Vec<(usize, Position)>,
+    globals: BTreeMap<String, Json>,
+    functions: BTreeMap<String, SavedFunction>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedFunction {
+    source: String,
+    line: usize,
+    statics: BTreeMap<String, Json>,
+}
+
+/// 64 bit FNV-1a, which unlike the standard library hasher is stable between releases
+pub fn document_hash(source: &str) -> String {
+    let hash = source
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Saves for a slot are kept next to the story, so `save("one")` in `story.md` writes
+/// `story.one.json`
+pub fn slot_path(story: &Path, slot: &str) -> Result<PathBuf> {
+    if slot.is_empty()
+        || !slot
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        bail!(
+            "Invalid save slot `{}`, slots may only contain letters, digits, `_` and `-`",
+            slot
+        );
+    }
+    Ok(story.with_extension(format!("{}.json", slot)))
+}
+
+fn to_json(value: &Value) -> Json {
+    match value {
+        Value::Str(s) => Json::from(s.as_str()),
+        Value::Int(i) => Json::from(*i),
+        Value::BigInt(i) => json!({ "big_int": i.to_string() }),
+        // JSON has no infinities or NaN, so write those as strings
+        Value::Float(f) if f.is_finite() => Json::from(*f),
+        Value::Float(f) => json!({ "float": f.to_string() }),
+        Value::Bool(b) => Json::from(*b),
+        Value::List(elements) => elements.iter().map(to_json).collect(),
+        Value::Map(entries) => json!({
+            "map": entries
+                .iter()
+                .map(|(key, value)| json!([to_json(key), to_json(value)]))
+                .collect::<Vec<_>>()
+        }),
+    }
+}
+
+fn from_json(json: &Json) -> Result<Value> {
+    let invalid = || anyhow!("Invalid value {} in save file", json);
+    Ok(match json {
+        Json::String(s) => Value::Str(s.clone()),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => Value::Int(i),
+            None => Value::Float(n.as_f64().ok_or_else(invalid)?),
+        },
+        Json::Bool(b) => Value::Bool(*b),
+        Json::Array(elements) => {
+            Value::List(elements.iter().map(from_json).collect::<Result<_>>()?)
+        }
+        Json::Object(object) => match object.iter().next() {
+            Some((tag, Json::String(s))) if object.len() == 1 && tag == "big_int" => {
+                Value::from_big(s.parse::<BigInt>().map_err(|_| invalid())?)
+            }
+            Some((tag, Json::String(s))) if object.len() == 1 && tag == "float" => {
+                Value::Float(s.parse().map_err(|_| invalid())?)
+            }
+            Some((tag, Json::Array(entries))) if object.len() == 1 && tag == "map" => {
+                let mut map = BTreeMap::new();
+                for entry in entries {
+                    match entry.as_array().map(Vec::as_slice) {
+                        Some([key, value]) => {
+                            map.insert(from_json(key)?, from_json(value)?);
+                        }
+                        _ => return Err(invalid()),
+                    }
+                }
+                Value::Map(map)
+            }
+            _ => return Err(invalid()),
+        },
+        Json::Null => return Err(invalid()),
+    })
+}
+
+fn scope_to_json(scope: &Scope) -> BTreeMap<String, Json> {
+    scope
+        .iter()
+        .map(|(name, value)| (name.clone(), to_json(value)))
+        .collect()
+}
+
+fn scope_from_json(json: &BTreeMap<String, Json>) -> Result<Scope> {
+    json.iter()
+        .map(|(name, value)| Ok((name.clone(), from_json(value)?)))
+        .collect()
+}
+
+/// Write a checkpoint to a file, to be resumed from the start of its step
+pub(crate) fn save(path: &Path, document_hash: &str, checkpoint: &Checkpoint) -> Result<()> {
+    let save = Save {
+        version: SAVE_VERSION,
+        document_hash: document_hash.to_owned(),
+        section: checkpoint.section.clone(),
+        position: checkpoint.position.clone(),
+        call_stack: checkpoint.call_stack.clone(),
+        globals: scope_to_json(&checkpoint.globals),
+        functions: checkpoint
+            .functions
+            .iter()
+            .map(|(name, function)| {
+                let saved = SavedFunction {
+                    source: function.source.clone(),
+                    line: function.line,
+                    statics: scope_to_json(&function.static_variables),
+                };
+                (name.clone(), saved)
+            })
+            .collect(),
+    };
+
+    let json = serde_json::to_string_pretty(&save)?;
+    fs::write(path, json).with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// Replace the global variables, functions and call stack of a context with those from a save file,
+/// returning the section and the position in it to resume from
+pub(crate) fn load(
+    path: &Path,
+    document_hash: &str,
+    context: &mut Context,
+) -> Result<(String, Position)> {
+    let json =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let json: Json = serde_json::from_str(&json)
+        .with_context(|| format!("Invalid save file {}", path.display()))?;
+    match json.get("version").and_then(Json::as_u64) {
+        Some(SAVE_VERSION) => (),
+        version => bail!(RundownError::UnsupportedSaveVersion {
+            path: path.display().to_string(),
+            version: version.map_or_else(|| "missing".to_owned(), |v| v.to_string()),
+        }),
+    }
+    let save: Save = serde_json::from_value(json)
+        .with_context(|| format!("Invalid save file {}", path.display()))?;
+    if save.document_hash != document_hash {
+        bail!(RundownError::StaleSave(path.display().to_string()));
+    }
+
+    let mut function_contexts = HashMap::new();
+    for (name, saved) in &save.functions {
+        let statement = ast::parse(&saved.source, saved.line)?.into_iter().next();
+        let (parameters, statements) = match statement.map(|statement| statement.kind) {
+            Some(StatementKind::FunctionDefinition {
+                parameters,
+                statements,
+                ..
+            }) => (parameters, statements),
+            _ => bail!("Invalid definition of function `{}` in save file", name),
+        };
+        let function = FunctionContext {
+            parameters,
+            statements,
+            static_variables: scope_from_json(&saved.statics)?,
+            source: saved.source.clone(),
+            line: saved.line,
+        };
+        function_contexts.insert(name.clone(), function);
+    }
+
+    context.global_variables = scope_from_json(&save.globals)?;
+    context.function_contexts = function_contexts;
+    context.call_stack = save.call_stack;
+    Ok((save.section, save.position))
+}
+
+/// `save(slot)` writes the progress of the story as it was when the current step started, so that
+/// loading it does not repeat whatever the step did before saving
+pub fn save_builtin(story: PathBuf, document_hash: String) -> impl Builtin {
+    move |context: &mut Context, arguments: &[Value]| {
+        let slot = slot_argument("save", arguments)?;
+        let checkpoint = context
+            .checkpoint
+            .as_ref()
+            .ok_or_else(|| anyhow!("No section is running"))?;
+        save(&slot_path(&story, slot)?, &document_hash, checkpoint)?;
+        Ok(ExpressionResult::Value(Value::Bool(true)))
+    }
+}
+
+/// `load(slot)` jumps to where a slot was saved, or returns false if it has not been saved
+pub fn load_builtin(story: PathBuf, document_hash: String) -> impl Builtin {
+    move |context: &mut Context, arguments: &[Value]| {
+        let slot = slot_argument("load", arguments)?;
+        let path = slot_path(&story, slot)?;
+        if !path.exists() {
+            return Ok(ExpressionResult::Value(Value::Bool(false)));
+        }
+        let (section, position) = load(&path, &document_hash, context)?;
+        context.resume = Some(position);
+        Ok(ExpressionResult::Goto(section))
+    }
+}
+
+fn slot_argument<'a>(name: &str, arguments: &'a [Value]) -> Result<&'a str> {
+    match arguments {
+        [Value::Str(slot)] => Ok(slot),
+        [v] => bail!(RundownError::InvalidArgument {
+            name: name.to_owned(),
+            expected: "a string",
+            actual: v.type_name(),
+        }),
+        _ => bail!(RundownError::ArityMismatch {
+            name: name.to_owned(),
+            expected: 1,
+            actual: arguments.len(),
+        }),
+    }
+}
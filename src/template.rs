@@ -0,0 +1,243 @@
+use ::markdown::{Block, ListItem, Span};
+use anyhow::{bail, Context as _, Result};
+
+use crate::ast::{self, Expression, ExpressionKind};
+use crate::eval::{Context, Value};
+use crate::markdown::restore_placeholders;
+
+pub const OPEN: &str = "{{";
+pub const CLOSE: &str = "}}";
+
+/// Split a template on the `|` between filters, ignoring `||` and anything inside strings
+fn split_filters(template: &str) -> Vec<&str> {
+    let mut ret = vec![];
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let bytes = template.as_bytes();
+    for (i, &byte) in bytes.iter().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            b'|' if !in_string
+                && bytes.get(i + 1) != Some(&b'|')
+                && (i == 0 || bytes[i - 1] != b'|') =>
+            {
+                ret.push(&template[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    ret.push(&template[start..]);
+    ret
+}
+
+/// Evaluate the contents of a placeholder. Filters are builtins which are given the value as
+/// their first argument, so `name | upper` is `upper(name)` and `price | round(2)` is
+/// `round(price, 2)`.
+fn evaluate(template: &str, context: &mut Context) -> Result<Value> {
+    let mut parts = split_filters(template).into_iter();
+    let mut value = ast::parse_expression(parts.next().unwrap_or_default(), 1)?;
+    for source in parts {
+        let filter = ast::parse_expression(source, 1)?;
+        let (name, mut arguments) = match filter.kind {
+            ExpressionKind::Ident(name) => (name, vec![]),
+            ExpressionKind::FunctionCall { name, arguments } => (name, arguments),
+            _ => bail!(
+                "Filters must be the name of a function, but got `{}`",
+                source.trim()
+            ),
+        };
+        arguments.insert(0, value);
+        value = Expression {
+            kind: ExpressionKind::FunctionCall { name, arguments },
+            span: filter.span,
+        };
+    }
+    context.eval_value(&value)
+}
+
+/// Evaluate the condition of an `<!-- if -->` marker
+pub fn evaluate_condition(condition: &str, context: &mut Context) -> Result<bool> {
+    let expression = ast::parse_expression(condition, 1)?;
+    Ok(context.eval_value(&expression)?.as_bool())
+}
+
+/// Replace each `{{ expression }}` placeholder in some text with its value. `\{{` is a literal
+/// `{{`.
+pub fn render(text: &str, context: &mut Context) -> Result<String> {
+    let text = restore_placeholders(text);
+    let mut ret = String::new();
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find(OPEN) {
+        if rest[..start].ends_with('\\') {
+            ret.push_str(&rest[..start - 1]);
+            ret.push_str(OPEN);
+            rest = &rest[start + OPEN.len()..];
+            continue;
+        }
+
+        ret.push_str(&rest[..start]);
+        let template = &rest[start + OPEN.len()..];
+        let end = match template.find(CLOSE) {
+            Some(end) => end,
+            None => bail!("Template `{}` is missing a closing `{}`", rest, CLOSE),
+        };
+        let value = evaluate(&template[..end], context)
+            .with_context(|| format!("In template `{}{}{}`", OPEN, &template[..end], CLOSE))?;
+        ret.push_str(&value.to_string());
+        rest = &template[end + CLOSE.len()..];
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+fn render_spans(spans: &[Span], context: &mut Context) -> Result<Vec<Span>> {
+    spans
+        .iter()
+        .map(|span| {
+            Ok(match span {
+                Span::Text(text) => Span::Text(render(text, context)?),
+                Span::Link(spans, url, title) => {
+                    Span::Link(render_spans(spans, context)?, url.clone(), title.clone())
+                }
+                Span::RefLink(spans, text, reference) => Span::RefLink(
+                    render_spans(spans, context)?,
+                    text.clone(),
+                    reference.clone(),
+                ),
+                Span::Emphasis(spans) => Span::Emphasis(render_spans(spans, context)?),
+                Span::Strong(spans) => Span::Strong(render_spans(spans, context)?),
+                // Inline code is shown as written
+                Span::Code(code) => Span::Code(restore_placeholders(code)),
+                span => span.clone(),
+            })
+        })
+        .collect()
+}
+
+fn render_list_items(items: &[ListItem], context: &mut Context) -> Result<Vec<ListItem>> {
+    items
+        .iter()
+        .map(|item| {
+            Ok(match item {
+                ListItem::Simple(spans) => ListItem::Simple(render_spans(spans, context)?),
+                ListItem::Paragraph(blocks) => ListItem::Paragraph(render_blocks(blocks, context)?),
+            })
+        })
+        .collect()
+}
+
+fn render_blocks(blocks: &[Block], context: &mut Context) -> Result<Vec<Block>> {
+    blocks
+        .iter()
+        .map(|block| render_block(block, context))
+        .collect()
+}
+
+/// Fill in the placeholders in the prose of a block, using the current values of global variables.
+/// Code blocks are left as they are.
+pub fn render_block(block: &Block, context: &mut Context) -> Result<Block> {
+    Ok(match block {
+        // Headings in a section start new sections, so this is only reached by those in quotes
+        Block::Header(spans, level) => Block::Header(render_spans(spans, context)?, *level),
+        Block::Paragraph(spans) => Block::Paragraph(render_spans(spans, context)?),
+        Block::Blockquote(blocks) => Block::Blockquote(render_blocks(blocks, context)?),
+        Block::OrderedList(items, list_type) => {
+            Block::OrderedList(render_list_items(items, context)?, list_type.clone())
+        }
+        Block::UnorderedList(items) => Block::UnorderedList(render_list_items(items, context)?),
+        Block::Raw(text) => Block::Raw(render(text, context)?),
+        Block::CodeBlock(syntax, code) => {
+            Block::CodeBlock(syntax.clone(), restore_placeholders(code))
+        }
+        block => block.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::builtins::builtins;
+    use crate::document::Document;
+    use crate::markdown::SectionBlock;
+    use ::markdown::generate_markdown;
+
+    fn context() -> Context {
+        let mut context = Context::new(builtins());
+        let statements = ast::parse("let global gold = 3; let global name = \"ann\";", 1).unwrap();
+        context.eval(&statements).unwrap();
+        context
+    }
+
+    #[test]
+    fn placeholders_are_replaced_by_values() {
+        let mut context = context();
+        assert_eq!(
+            render(
+                "You have {{ gold }} gold, and {{gold * 2}} next turn",
+                &mut context
+            )
+            .unwrap(),
+            "You have 3 gold, and 6 next turn"
+        );
+    }
+
+    #[test]
+    fn filters_are_applied_in_order() {
+        let mut context = context();
+        assert_eq!(
+            render("{{ name | upper | repeat(2) }}", &mut context).unwrap(),
+            "ANNANN"
+        );
+        assert_eq!(
+            render("{{ gold > 5 || name == \"a|b\" }}", &mut context).unwrap(),
+            "false"
+        );
+    }
+
+    #[test]
+    fn braces_can_be_escaped() {
+        let mut context = context();
+        assert_eq!(
+            render("\\{{ gold }} is {{ gold }}", &mut context).unwrap(),
+            "{{ gold }} is 3"
+        );
+    }
+
+    #[test]
+    fn invalid_placeholders_are_errors() {
+        let mut context = context();
+        assert!(render("{{ missing }}", &mut context).is_err());
+        assert!(render("{{ gold ", &mut context).is_err());
+        assert!(render("{{ gold | 1 }}", &mut context).is_err());
+    }
+
+    #[test]
+    fn markdown_inside_placeholders_is_left_alone() {
+        let mut context = context();
+        let statements = ast::parse("let global max_hp = 4; let global best_hp = 5;", 1).unwrap();
+        context.eval(&statements).unwrap();
+        let document = Document::parse(
+            "# Hero\n\n{{ max_hp * 2 }} or {{ best_hp * max_hp }}, _{{ \"a_b\" | upper }}_\n",
+        );
+        let rendered = document.sections["hero"]
+            .iter()
+            .map(|SectionBlock { block, .. }| render_block(block, &mut context).unwrap())
+            .collect();
+        assert_eq!(generate_markdown(rendered), "8 or 20, *A_B*");
+    }
+
+    #[test]
+    fn headings_in_quotes_are_rendered() {
+        let block = Block::Blockquote(vec![Block::Header(
+            vec![Span::Text("Dear {{ name }}".to_owned())],
+            2,
+        )]);
+        let rendered = render_block(&block, &mut context()).unwrap();
+        assert_eq!(generate_markdown(vec![rendered]), "> ## Dear ann");
+    }
+}